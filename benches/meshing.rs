@@ -0,0 +1,90 @@
+//! Times meshing a large heightmap and encoding it as STL facets,
+//! on one thread and on all cores, and checks that both give the same bytes.
+//!
+//! Run with `cargo bench --bench meshing`, optionally followed by `-- SIZE`
+//! (4096 by default, for a 4096 x 4096 map).
+
+use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+extern crate nalgebra_glm as glm;
+use glm::{Vec2, TVec2};
+use heightmap_to_stl::geometry::heightmap::Heightmap;
+use heightmap_to_stl::geometry::triangle::{write_facet, Triangle, STL_FACET_SIZE};
+use heightmap_to_stl::geometry::units::Unit;
+
+/// Rolling hills, in steps of 0.25 so that neighbouring samples are often equal
+/// (as in real images, where their tops get merged)
+fn terrain(size: i32) -> Heightmap {
+    let samples = (0..size * size)
+        .map(|index| {
+            let x = (index % size) as f32;
+            let y = (index / size) as f32;
+            let height = ((x * 0.011).sin() + (y * 0.017).cos() + (x * 0.003 + y * 0.005).sin() + 3.) * 4.;
+            (height * 4.).round() / 4.
+        })
+        .collect();
+    Heightmap {
+        size: TVec2::new(size, size),
+        scale: Vec2::new(1., 1.),
+        samples,
+        colors: None,
+        coverage: None,
+        invert_y: false,
+        unit: Unit::Millimeter
+    }
+}
+
+/// Size and hash of a mesh encoded as binary STL facets
+#[derive(PartialEq, Eq)]
+struct Checksum {
+    triangle_count: usize,
+    hash: u64
+}
+
+/// Meshes the heightmap and encodes it as STL facets, with `fold_bands`
+/// or `try_fold_bands_serial`
+fn encode(heightmap: &Heightmap, parallel: bool) -> Checksum {
+    let mut hasher = DefaultHasher::new();
+    let mut size = 0;
+    let init = Vec::<u8>::new;
+    let fold = |facets: &mut Vec<u8>, triangle: Triangle, _surface| {
+        let _ = write_facet(facets, &triangle, 0);
+    };
+    let mut emit = |facets: Vec<u8>| {
+        size += facets.len();
+        hasher.write(&facets);
+    };
+    if parallel {
+        heightmap.fold_bands(false, init, fold, emit);
+    } else {
+        let Ok(()) = heightmap.try_fold_bands_serial::<_, Infallible, _, _, _>(false, init, fold, |facets| {
+            emit(facets);
+            Ok(())
+        });
+    }
+    Checksum { triangle_count: size / STL_FACET_SIZE, hash: hasher.finish() }
+}
+
+fn time<F: FnOnce() -> Checksum>(name: &str, mesh: F) -> (Checksum, Duration) {
+    let start = Instant::now();
+    let checksum = mesh();
+    let elapsed = start.elapsed();
+    println!("{:<10} {:>8.3} s  {} triangles", name, elapsed.as_secs_f64(), checksum.triangle_count);
+    (checksum, elapsed)
+}
+
+fn main() {
+    let size = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse::<i32>().ok())
+        .unwrap_or(4096);
+    let heightmap = terrain(size);
+    println!("meshing a {} x {} heightmap on {} threads", size, size, rayon::current_num_threads());
+
+    let (serial, serial_time) = time("serial", || encode(&heightmap, false));
+    let (parallel, parallel_time) = time("parallel", || encode(&heightmap, true));
+    assert!(serial == parallel, "parallel meshing gave different facets");
+    println!("speedup    {:.2}x", serial_time.as_secs_f64() / parallel_time.as_secs_f64());
+}
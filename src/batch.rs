@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use crate::cli::*;
+
+/// The outcome of converting one file of a batch
+struct BatchEntry {
+    input: String,
+    output: String,
+    /// Triangles written and size of the output file, or the error message
+    result: Result<(usize, u64), String>
+}
+
+/// True for files a batch picks up from a directory: images, and text heightmaps
+fn is_heightmap_file(path: &Path) -> bool {
+    let is_text = path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("txt"));
+    #[cfg(feature = "image-formats")]
+    let is_image = image::ImageFormat::from_path(path).is_ok();
+    #[cfg(not(feature = "image-formats"))]
+    let is_image = false;
+    path.is_file() && (is_text || is_image)
+}
+
+/// Expands directories (not recursively) and glob patterns into a list of files.
+///
+/// Patterns and directories that match nothing are reported, but skipped.
+fn expand_inputs(inputs: &[String]) -> Result<Vec<String>, CliError> {
+    let mut files = Vec::<String>::new();
+    for input in inputs {
+        let path = Path::new(input);
+        let matched = if path.is_dir() {
+            let mut entries: Vec<String> = fs::read_dir(path)
+                .map_err(|e| CliError {
+                    code: EXIT_INPUT_ERROR,
+                    message: format!("Could not list directory {}: {}", input, e)
+                })?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| is_heightmap_file(path))
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect();
+            entries.sort();
+            entries
+        } else if input.contains(['*', '?', '[']) {
+            glob::glob(input)
+                .map_err(|e| CliError {
+                    code: 2,
+                    message: format!("Invalid pattern {}: {}", input, e)
+                })?
+                .filter_map(|entry| entry.ok())
+                .filter(|path| path.is_file())
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect()
+        } else {
+            vec![input.clone()]
+        };
+        if matched.is_empty() {
+            eprintln!("No heightmaps found in {}", input);
+        }
+        files.extend(matched);
+    }
+    Ok(files)
+}
+
+/// Fills in an output name template for the `index`th (from 1) input
+fn output_name(template: &str, input: &str, index: usize) -> String {
+    let path = Path::new(input);
+    let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+    let stem = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    template
+        .replace("{stem}", &stem)
+        .replace("{name}", &name)
+        .replace("{index}", &index.to_string())
+}
+
+fn print_summary(entries: &[BatchEntry]) {
+    let input_width = entries.iter()
+        .map(|entry| entry.input.chars().count())
+        .chain(std::iter::once("input".len()))
+        .max()
+        .unwrap_or(0);
+    println!("{:<input_width$}  {:<6}  {:>10}  {:>10}  output",
+        "input", "status", "triangles", "size");
+    for entry in entries {
+        match &entry.result {
+            Ok((triangles, size)) => println!("{:<input_width$}  {:<6}  {:>10}  {:>10}  {}",
+                entry.input, "ok", triangles, format_size(*size), entry.output),
+            Err(message) => println!("{:<input_width$}  {:<6}  {}",
+                entry.input, "FAILED", message)
+        }
+    }
+    let failed = entries.iter().filter(|entry| entry.result.is_err()).count();
+    println!("{} converted, {} failed", entries.len() - failed, failed);
+}
+
+/// Converts every input into the output directory, several files at a time.
+///
+/// A file that fails doesn't stop the others; the batch fails at the end
+/// if any of them did.
+pub fn batch(args: &BatchArgs) -> Result<(), CliError> {
+    let inputs = expand_inputs(&args.inputs)?;
+    if inputs.is_empty() {
+        return Err(CliError {
+            code: EXIT_INPUT_ERROR,
+            message: "No heightmaps to convert".to_string()
+        });
+    }
+    fs::create_dir_all(&args.out_dir).map_err(|e| CliError {
+        code: EXIT_OUTPUT_ERROR,
+        message: format!("Could not create directory {}: {}", args.out_dir, e)
+    })?;
+    let preset = resolve_preset(&args.settings, &args.export)?;
+
+    // Two inputs named alike would overwrite each other's output, so only the first is converted
+    let mut used_outputs = HashSet::<String>::new();
+    let entries: Vec<Mutex<BatchEntry>> = inputs.into_iter().enumerate()
+        .map(|(index, input)| {
+            let output = Path::new(&args.out_dir)
+                .join(output_name(&args.name, &input, index + 1))
+                .to_string_lossy()
+                .into_owned();
+            let result = if used_outputs.insert(output.clone()) {
+                Ok((0, 0))
+            } else {
+                Err(format!("{} is also written by an earlier input", output))
+            };
+            Mutex::new(BatchEntry { input, output, result })
+        })
+        .collect();
+
+    let jobs = args.jobs
+        .or_else(|| thread::available_parallelism().ok().map(|jobs| jobs.get()))
+        .unwrap_or(1)
+        .clamp(1, entries.len());
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                while let Some(entry) = entries.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let mut entry = entry.lock().unwrap();
+                    if entry.result.is_err() {
+                        continue;
+                    }
+                    let format = args.export.output_format(&entry.output);
+                    entry.result = convert_file(
+                        &entry.input, &entry.output, &preset.settings, format, &preset.export)
+                        .map_err(|e| e.message)
+                        .map(|triangles| {
+                            let size = fs::metadata(&entry.output).map_or(0, |metadata| metadata.len());
+                            (triangles, size)
+                        });
+                }
+            });
+        }
+    });
+
+    let entries: Vec<BatchEntry> = entries.into_iter()
+        .map(|entry| entry.into_inner().unwrap())
+        .collect();
+    print_summary(&entries);
+    if entries.iter().any(|entry| entry.result.is_err()) {
+        return Err(CliError {
+            code: EXIT_BATCH_FAILED,
+            message: "Some heightmaps could not be converted".to_string()
+        });
+    }
+    Ok(())
+}
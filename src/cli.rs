@@ -0,0 +1,941 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::process::ExitCode;
+use std::thread;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+extern crate nalgebra_glm as glm;
+use glm::Vec2;
+use crate::export::*;
+use crate::geometry::ReadError;
+use crate::geometry::heightmap::*;
+use crate::geometry::lithophane::*;
+use crate::geometry::stats::{heightmap_stats, HeightmapStats};
+use crate::geometry::units::Unit;
+use crate::geometry::ply::PlyFormat;
+use crate::geometry::tiles::*;
+use crate::geometry::wrap::*;
+use crate::geometry::hollow::Hollow;
+use crate::geometry::mask::Mask;
+use crate::geometry::contours::*;
+use crate::preset::*;
+use crate::settings::*;
+use crate::watch::*;
+
+/// Exit codes, other than 0 for success.
+/// (Invalid command line arguments exit with 2)
+pub const EXIT_INPUT_ERROR: u8 = 3;
+pub const EXIT_OUTPUT_ERROR: u8 = 4;
+pub const EXIT_BATCH_FAILED: u8 = 5;
+
+/// Input or output file name for standard input or output
+pub const STDIO_PATH: &str = "-";
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  2  invalid arguments
+  3  the heightmap could not be read
+  4  the mesh could not be written
+  5  some files of a batch could not be converted";
+
+/// Converts heightmaps (images or text files) into printable meshes.
+///
+/// With no arguments, opens the graphical interface (if it was built with one).
+#[derive(Parser)]
+#[command(name = "heightmap_to_stl", version, after_help = EXIT_CODES_HELP,
+    args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Heightmap to convert with default settings (same as `convert INPUT OUTPUT`),
+    /// or - for standard input
+    #[arg(requires = "output")]
+    pub input: Option<String>,
+    /// Mesh file to write, or - for standard output
+    pub output: Option<String>
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Converts a heightmap into an STL, 3MF, PLY or GLB file
+    #[command(after_help = EXIT_CODES_HELP)]
+    Convert(ConvertArgs),
+    /// Converts many heightmaps into a directory, several at a time
+    #[command(after_help = EXIT_CODES_HELP)]
+    Batch(BatchArgs),
+    /// Prints the size and heights of a heightmap, and the size of its mesh
+    #[command(after_help = EXIT_CODES_HELP)]
+    Info(InfoArgs),
+    /// Turns an image into a lithophane, thick where it is dark and thin where it is bright
+    #[command(after_help = EXIT_CODES_HELP)]
+    Lithophane(LithophaneArgs),
+    /// Draws a heightmap's contour lines (the outlines of its parts above each level) as SVG or DXF
+    #[command(after_help = EXIT_CODES_HELP)]
+    Contours(ContoursArgs),
+    /// Opens a heightmap in the graphical interface
+    #[cfg(feature = "gui")]
+    Preview(PreviewArgs),
+    /// Opens the graphical interface
+    #[cfg(feature = "gui")]
+    Gui
+}
+
+#[derive(Args)]
+pub struct ConvertArgs {
+    /// Heightmap file (image or text), or - for standard input
+    pub input: String,
+    /// Mesh file to write, or - for standard output
+    pub output: String,
+    /// Also save the settings used to a preset file, to repeat the conversion later
+    #[arg(long, value_name = "FILE")]
+    pub save_preset: Option<String>,
+    /// Keep running, and convert again whenever the input (or preset) file changes
+    #[arg(short, long)]
+    pub watch: bool,
+    #[command(flatten)]
+    pub settings: SettingsArgs,
+    #[command(flatten)]
+    pub export: ExportArgs,
+    #[command(flatten)]
+    pub tiles: TileArgs
+}
+
+#[derive(Args)]
+pub struct BatchArgs {
+    /// Heightmap files, directories of them, or glob patterns (such as "maps/*.png")
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    /// Directory to write the meshes to (created if it doesn't exist)
+    #[arg(short, long, value_name = "DIR")]
+    pub out_dir: String,
+    /// Name of each mesh file. {stem} is replaced with the input file name
+    /// without its extension, {name} with the whole input file name,
+    /// and {index} with the input's position in the batch (from 1)
+    #[arg(long, value_name = "TEMPLATE", default_value = "{stem}.stl")]
+    pub name: String,
+    /// Number of files to convert at once [default: number of processors]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+    #[command(flatten)]
+    pub settings: SettingsArgs,
+    #[command(flatten)]
+    pub export: ExportArgs
+}
+
+#[derive(Args)]
+pub struct InfoArgs {
+    /// Heightmap file (image or text), or - for standard input
+    pub input: String,
+    /// Print the statistics as JSON
+    #[arg(long)]
+    pub json: bool,
+    #[command(flatten)]
+    pub settings: SettingsArgs
+}
+
+#[derive(Args)]
+pub struct LithophaneArgs {
+    /// Image file, or - for standard input
+    pub input: String,
+    /// Mesh file to write, or - for standard output
+    pub output: String,
+    /// Shape to bend the lithophane into (given instead of --wrap)
+    #[arg(long, value_enum, default_value = "flat", conflicts_with_all = ["wrap", "wrap_angle", "wrap_curve"])]
+    pub shape: ShapeArg,
+    /// Width of the image, not counting the frame
+    #[arg(long, default_value_t = 100.)]
+    pub width: f32,
+    /// Thickness of white areas
+    #[arg(long, value_name = "THICKNESS", default_value_t = 0.8)]
+    pub min_thickness: f32,
+    /// Thickness of black areas
+    #[arg(long, value_name = "THICKNESS", default_value_t = 3.)]
+    pub max_thickness: f32,
+    /// Width of a frame around the image (only at the top and bottom of cylinders)
+    #[arg(long, value_name = "WIDTH", default_value_t = 0.)]
+    pub frame: f32,
+    /// Thickness of a foot along the bottom edge to stand on
+    #[arg(long, value_name = "THICKNESS", default_value_t = 0.)]
+    pub stand: f32,
+    /// Angle covered by a curved lithophane, in degrees
+    #[arg(long, value_name = "DEGREES", default_value_t = 120.,
+        value_parser = parse_arc_angle)]
+    pub angle: f32,
+    /// Unit of the sizes, recorded in 3MF and GLB files [default: mm]
+    #[arg(long, value_enum)]
+    pub unit: Option<UnitArg>,
+    #[command(flatten)]
+    pub export: ExportArgs
+}
+
+#[derive(Args)]
+pub struct ContoursArgs {
+    /// Heightmap file (image or text), or - for standard input
+    pub input: String,
+    /// SVG or DXF file to write, or - for standard output
+    pub output: String,
+    /// Draw a contour at every multiple of HEIGHT, from 0 up
+    #[arg(long, value_name = "HEIGHT", required_unless_present = "levels", conflicts_with = "levels")]
+    pub interval: Option<f32>,
+    /// Draw contours at these heights, as HEIGHT,HEIGHT,...
+    #[arg(long, value_name = "HEIGHTS", value_delimiter = ',')]
+    pub levels: Vec<f32>,
+    /// Drawing format [default: from the output file extension, or SVG]
+    #[arg(long, value_enum)]
+    pub format: Option<ContourFormatArg>,
+    #[command(flatten)]
+    pub settings: SettingsArgs
+}
+
+#[derive(Args)]
+pub struct PreviewArgs {
+    /// Heightmap file (image or text)
+    pub input: String,
+    #[command(flatten)]
+    pub settings: SettingsArgs
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum InputFormatArg {
+    Auto,
+    Image,
+    Text
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ChannelArg {
+    Max,
+    Red,
+    Green,
+    Blue,
+    Luma,
+    Alpha
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum UnitArg {
+    Um,
+    Mm,
+    Cm,
+    In,
+    Ft,
+    M
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormatArg {
+    Stl,
+    #[value(name = "3mf")]
+    ThreeMf,
+    Ply,
+    Glb
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum StlColorsArg {
+    None,
+    Image,
+    HeightBands
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum WrapArg {
+    None,
+    Arc,
+    Cylinder,
+    Sphere,
+    Curve
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ContourFormatArg {
+    Svg,
+    Dxf
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ShapeArg {
+    Flat,
+    Curved,
+    Cylinder
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum JointArg {
+    None,
+    Pins,
+    Dovetail
+}
+
+/// Import and meshing options.
+/// Options given here override the ones from the preset file.
+#[derive(Args)]
+pub struct SettingsArgs {
+    /// TOML file with import, meshing and export settings
+    #[arg(long, value_name = "FILE")]
+    pub preset: Option<String>,
+    /// Input format [default: auto]
+    #[arg(long, value_enum)]
+    pub input_format: Option<InputFormatArg>,
+    /// Image channel that sets the height [default: max]
+    #[arg(long, value_enum)]
+    pub channel: Option<ChannelArg>,
+    /// Height of a full intensity pixel [default: 1/32 of the larger image dimension]
+    #[arg(long, value_name = "HEIGHT")]
+    pub max_height: Option<f32>,
+    /// Don't add random texture to image pixels with no red
+    #[arg(long)]
+    pub no_noise: bool,
+    /// Size of one sample, as X,Y [default: from the file, or 1,1 for images]
+    #[arg(long, value_name = "X,Y", value_parser = parse_pair)]
+    pub scale: Option<Vec2>,
+    /// Fit the footprint within a WIDTH by DEPTH rectangle, keeping its proportions
+    /// (0 leaves a direction free)
+    #[arg(long, value_name = "WIDTH,DEPTH", value_parser = parse_pair)]
+    pub fit: Option<Vec2>,
+    /// Multiplies every height [default: 1]
+    #[arg(long, value_name = "FACTOR")]
+    pub z_scale: Option<f32>,
+    /// Scale the heights so that the highest point is HEIGHT high (not counting the base)
+    #[arg(long, value_name = "HEIGHT")]
+    pub relief: Option<f32>,
+    /// Snap the heights to COUNT evenly spaced levels, for a terraced model
+    #[arg(long, value_name = "COUNT", conflicts_with = "layer_height")]
+    pub terraces: Option<u32>,
+    /// Snap the heights to multiples of HEIGHT, such as a printer's layer height
+    /// or the thickness of plywood sheets
+    #[arg(long, value_name = "HEIGHT")]
+    pub layer_height: Option<f32>,
+    /// Thickness of a solid base added under the heightmap [default: 0]
+    #[arg(long, value_name = "THICKNESS")]
+    pub base: Option<f32>,
+    /// Unit of scales, heights and sizes, recorded in 3MF and GLB files [default: mm]
+    #[arg(long, value_enum)]
+    pub unit: Option<UnitArg>,
+    /// Leave image pixels that are more than half transparent out of the mesh
+    #[arg(long)]
+    pub alpha_mask: bool,
+    /// Image whose dark or transparent pixels are left out of the mesh,
+    /// stretched over the heightmap
+    #[arg(long, value_name = "FILE")]
+    pub mask: Option<String>,
+    /// Keep only the samples inside a polygon with corners X,Y X,Y ...,
+    /// in samples from the first one (with y going down the rows, as in images)
+    #[arg(long, value_name = "X,Y", value_parser = parse_pair, num_args = 3..)]
+    pub mask_polygon: Vec<Vec2>,
+    /// Keep only the samples inside an SVG path, given as its path data
+    /// (as in the `d` attribute), in the same coordinates as --mask-polygon
+    #[arg(long, value_name = "PATH")]
+    pub mask_path: Option<String>,
+    /// Keep the samples outside the mask instead
+    #[arg(long)]
+    pub invert_mask: bool
+}
+
+/// Options for the mesh file
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Output format [default: from the output file extension, or STL]
+    #[arg(long, value_enum)]
+    pub output_format: Option<OutputFormatArg>,
+    /// Color of walls in PLY and GLB files, and in STL files with image colors,
+    /// as #RRGGBB or R,G,B
+    #[arg(long, value_name = "COLOR", value_parser = parse_color)]
+    pub wall_color: Option<[u8; 3]>,
+    /// Color of the base in PLY and GLB files, as #RRGGBB or R,G,B
+    #[arg(long, value_name = "COLOR", value_parser = parse_color)]
+    pub base_color: Option<[u8; 3]>,
+    /// Write ASCII rather than binary PLY
+    #[arg(long)]
+    pub ply_ascii: bool,
+    /// Leave vertex colors out of GLB files
+    #[arg(long)]
+    pub glb_no_vertex_colors: bool,
+    /// Embed the source image as a texture in GLB files
+    #[arg(long)]
+    pub glb_texture: bool,
+    /// Facet colors stored in STL attribute bytes [default: none]
+    #[arg(long, value_enum)]
+    pub stl_colors: Option<StlColorsArg>,
+    /// Bend the solid around a surface, with heights pointing outwards [default: none]
+    #[arg(long, value_enum)]
+    pub wrap: Option<WrapArg>,
+    /// Angle covered by an arc, in degrees [default: 120]
+    #[arg(long, value_name = "DEGREES", value_parser = parse_arc_angle)]
+    pub wrap_angle: Option<f32>,
+    /// Points of the curve to wrap around, as X,Y X,Y ...
+    /// (closed if the last point is the first)
+    #[arg(long, value_name = "X,Y", value_parser = parse_pair, num_args = 2..)]
+    pub wrap_curve: Vec<Vec2>,
+    /// Hollow the solid out, leaving walls, floor and roof at least THICKNESS thick
+    #[arg(long, value_name = "THICKNESS")]
+    pub hollow: Option<f32>,
+    /// Trace the outline of masks and holes smoothly between samples,
+    /// rather than along their edges
+    #[arg(long)]
+    pub smooth_outline: bool,
+    /// Add a drain hole through the floor of a hollow solid, centered on X,Y
+    /// (can be given several times)
+    #[arg(long, value_name = "X,Y", value_parser = parse_pair)]
+    pub drain_hole: Vec<Vec2>,
+    /// Diameter of drain holes [default: 3]
+    #[arg(long, value_name = "DIAMETER")]
+    pub drain_diameter: Option<f32>
+}
+
+/// Options for splitting the mesh into tiles, each written to its own file
+/// (the output file name with _r<ROW>_c<COLUMN> added)
+#[derive(Args)]
+pub struct TileArgs {
+    /// Split the mesh into a grid of COLUMNS by ROWS tiles
+    #[arg(long, value_name = "COLUMNS,ROWS", value_parser = parse_grid, group = "tiling")]
+    pub tiles: Option<(u32, u32)>,
+    /// Split the mesh into as few tiles as fit within WIDTH by DEPTH, joints included
+    /// (0 leaves a direction unsplit)
+    #[arg(long, value_name = "WIDTH,DEPTH", value_parser = parse_pair, group = "tiling")]
+    pub max_tile_size: Option<Vec2>,
+    /// How neighbouring tiles fit together [default: none]
+    #[arg(long, value_enum, requires = "tiling")]
+    pub joints: Option<JointArg>,
+    /// Width of each pin or dovetail [default: a quarter of the shorter side of a tile]
+    #[arg(long, value_name = "SIZE", requires = "tiling")]
+    pub joint_size: Option<f32>,
+    /// Gap left around pins and dovetails, so that they fit their holes [default: 0]
+    #[arg(long, value_name = "GAP", requires = "tiling")]
+    pub joint_clearance: Option<f32>
+}
+
+fn parse_pair(text: &str) -> Result<Vec2, String> {
+    let (x, y) = text.split_once(',').ok_or("expected two numbers, as X,Y")?;
+    let x = x.trim().parse::<f32>().map_err(|e| e.to_string())?;
+    let y = y.trim().parse::<f32>().map_err(|e| e.to_string())?;
+    Ok(Vec2::new(x, y))
+}
+
+fn parse_arc_angle(text: &str) -> Result<f32, String> {
+    let angle = text.parse::<f32>().map_err(|e| e.to_string())?;
+    if angle <= 0. || angle >= 360. {
+        return Err("expected an angle between 0 and 360 degrees".to_string());
+    }
+    Ok(angle)
+}
+
+fn parse_grid(text: &str) -> Result<(u32, u32), String> {
+    let (columns, rows) = text.split_once(',').ok_or("expected two counts, as COLUMNS,ROWS")?;
+    let columns = columns.trim().parse::<u32>().map_err(|e| e.to_string())?;
+    let rows = rows.trim().parse::<u32>().map_err(|e| e.to_string())?;
+    if columns == 0 || rows == 0 {
+        return Err("expected at least one column and row".to_string());
+    }
+    Ok((columns, rows))
+}
+
+fn parse_color(text: &str) -> Result<[u8; 3], String> {
+    if let Some(hex) = text.strip_prefix('#') {
+        let value = u32::from_str_radix(hex, 16).map_err(|e| e.to_string())?;
+        if hex.len() != 6 {
+            return Err("expected six hex digits, as #RRGGBB".to_string());
+        }
+        Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+    } else {
+        let parts: Vec<&str> = text.split(',').collect();
+        if parts.len() != 3 {
+            return Err("expected #RRGGBB or R,G,B".to_string());
+        }
+        let mut color = [0u8; 3];
+        for (channel, part) in color.iter_mut().zip(parts) {
+            *channel = part.trim().parse().map_err(|e| format!("{}", e))?;
+        }
+        Ok(color)
+    }
+}
+
+impl SettingsArgs {
+    /// The preset file's settings, or the defaults if there isn't one
+    pub fn preset(&self) -> Result<Preset, CliError> {
+        match &self.preset {
+            Some(path) => Preset::read(path).map_err(|e| CliError {
+                code: EXIT_INPUT_ERROR,
+                message: format!("Could not read preset {}: {}", path, e)
+            }),
+            None => Ok(Preset::default())
+        }
+    }
+
+    /// Overrides the settings given on the command line
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(input_format) = self.input_format {
+            settings.input_format = match input_format {
+                InputFormatArg::Auto => InputFormat::Auto,
+                InputFormatArg::Image => InputFormat::Image,
+                InputFormatArg::Text => InputFormat::Text
+            };
+        }
+        if let Some(channel) = self.channel {
+            settings.image.channel = match channel {
+                ChannelArg::Max => Channel::Max,
+                ChannelArg::Red => Channel::Red,
+                ChannelArg::Green => Channel::Green,
+                ChannelArg::Blue => Channel::Blue,
+                ChannelArg::Luma => Channel::Luma,
+                ChannelArg::Alpha => Channel::Alpha
+            };
+        }
+        if self.max_height.is_some() {
+            settings.image.max_height = self.max_height;
+        }
+        if self.no_noise {
+            settings.image.noise = false;
+        }
+        if self.scale.is_some() {
+            settings.scale = self.scale;
+        }
+        if self.fit.is_some() {
+            settings.fit = self.fit;
+        }
+        if let Some(z_scale) = self.z_scale {
+            settings.z_scale = z_scale;
+        }
+        if self.relief.is_some() {
+            settings.relief = self.relief;
+        }
+        if self.terraces.is_some() {
+            settings.terraces = self.terraces;
+            settings.layer_height = None;
+        }
+        if self.layer_height.is_some() {
+            settings.layer_height = self.layer_height;
+        }
+        if let Some(base) = self.base {
+            settings.base = base;
+        }
+        if let Some(unit) = self.unit {
+            settings.unit = unit.to_unit();
+        }
+        if self.alpha_mask {
+            settings.image.alpha_mask = true;
+        }
+        if self.mask.is_some() {
+            settings.mask.get_or_insert_with(Mask::default).image = self.mask.clone();
+        }
+        if !self.mask_polygon.is_empty() {
+            settings.mask.get_or_insert_with(Mask::default).polygon =
+                self.mask_polygon.iter().map(|point| [point.x, point.y]).collect();
+        }
+        if self.mask_path.is_some() {
+            settings.mask.get_or_insert_with(Mask::default).path = self.mask_path.clone();
+        }
+        if self.invert_mask {
+            settings.mask.get_or_insert_with(Mask::default).invert = true;
+        }
+    }
+
+    /// The preset file's settings, with the command line's applied
+    pub fn to_settings(&self) -> Result<Settings, CliError> {
+        let mut settings = self.preset()?.settings;
+        self.apply(&mut settings);
+        Ok(settings)
+    }
+}
+
+impl UnitArg {
+    pub fn to_unit(self) -> Unit {
+        match self {
+            Self::Um => Unit::Micron,
+            Self::Mm => Unit::Millimeter,
+            Self::Cm => Unit::Centimeter,
+            Self::In => Unit::Inch,
+            Self::Ft => Unit::Foot,
+            Self::M => Unit::Meter
+        }
+    }
+}
+
+impl ExportArgs {
+    /// Output format for a mesh file
+    pub fn output_format(&self, output: &str) -> OutputFormat {
+        match self.output_format {
+            Some(OutputFormatArg::Stl) => OutputFormat::Stl,
+            Some(OutputFormatArg::ThreeMf) => OutputFormat::ThreeMf,
+            Some(OutputFormatArg::Ply) => OutputFormat::Ply,
+            Some(OutputFormatArg::Glb) => OutputFormat::Glb,
+            None => OutputFormat::from_path(output)
+        }
+    }
+
+    /// Overrides the export options given on the command line
+    pub fn apply(&self, options: &mut ExportOptions) {
+        if let Some(wall_color) = self.wall_color {
+            options.wall_color = wall_color;
+        }
+        if let Some(base_color) = self.base_color {
+            options.base_color = base_color;
+        }
+        if self.ply_ascii {
+            options.ply_format = PlyFormat::Ascii;
+        }
+        if self.glb_no_vertex_colors {
+            options.glb_vertex_colors = false;
+        }
+        if self.glb_texture {
+            options.glb_texture = true;
+        }
+        if let Some(stl_colors) = self.stl_colors {
+            options.stl_colors = match stl_colors {
+                StlColorsArg::None => StlColors::None,
+                StlColorsArg::Image => StlColors::Image,
+                StlColorsArg::HeightBands => StlColors::HeightBands
+            };
+        }
+        if let Some(wrap) = self.wrap {
+            let shape = match wrap {
+                WrapArg::None => None,
+                WrapArg::Arc => Some(WrapShape::Arc),
+                WrapArg::Cylinder => Some(WrapShape::Cylinder),
+                WrapArg::Sphere => Some(WrapShape::Sphere),
+                WrapArg::Curve => Some(WrapShape::Curve)
+            };
+            options.wrap = shape.map(|shape| Wrap {
+                shape,
+                ..options.wrap.take().unwrap_or_default()
+            });
+        }
+        if let Some(wrap) = &mut options.wrap {
+            if let Some(angle) = self.wrap_angle {
+                wrap.angle = angle;
+            }
+            if !self.wrap_curve.is_empty() {
+                wrap.points = self.wrap_curve.iter().map(|point| [point.x, point.y]).collect();
+            }
+        }
+        if self.smooth_outline {
+            options.smooth_outline = true;
+        }
+        if let Some(wall) = self.hollow {
+            options.hollow.get_or_insert_with(Hollow::default).wall = wall;
+        }
+        if let Some(hollow) = &mut options.hollow {
+            if !self.drain_hole.is_empty() {
+                hollow.drain_holes = self.drain_hole.iter().map(|point| [point.x, point.y]).collect();
+            }
+            if let Some(diameter) = self.drain_diameter {
+                hollow.drain_diameter = diameter;
+            }
+        }
+    }
+}
+
+impl TileArgs {
+    /// How to split the mesh, or None to write a single file
+    pub fn to_options(&self) -> Option<TileOptions> {
+        let layout = match (self.tiles, self.max_tile_size) {
+            (Some((columns, rows)), _) => TileLayout::Count { columns, rows },
+            (None, Some(max)) => TileLayout::MaxSize(max),
+            (None, None) => return None
+        };
+        Some(TileOptions {
+            layout,
+            joint: match self.joints {
+                None | Some(JointArg::None) => Joint::None,
+                Some(JointArg::Pins) => Joint::Pins,
+                Some(JointArg::Dovetail) => Joint::Dovetail
+            },
+            joint_size: self.joint_size,
+            clearance: self.joint_clearance.unwrap_or(0.)
+        })
+    }
+}
+
+/// The preset file (if any), overridden by the command line options
+pub fn resolve_preset(settings: &SettingsArgs, export: &ExportArgs) -> Result<Preset, CliError> {
+    let mut preset = settings.preset()?;
+    settings.apply(&mut preset.settings);
+    export.apply(&mut preset.export);
+    Ok(preset)
+}
+
+/// An error message, and the exit code to report it with
+pub struct CliError {
+    pub code: u8,
+    pub message: String
+}
+
+/// Reads a heightmap file, or standard input if `input` is "-"
+fn load(input: &str, settings: &Settings) -> Result<Heightmap, CliError> {
+    let result = if input == STDIO_PATH {
+        let mut bytes = Vec::<u8>::new();
+        std::io::stdin().lock().read_to_end(&mut bytes)
+            .map_err(ReadError::from)
+            .and_then(|_| settings.load_bytes(&bytes))
+    } else {
+        settings.load(input)
+    };
+    result.map_err(|e| CliError {
+        code: EXIT_INPUT_ERROR,
+        message: format!("Could not read heightmap {}: {}", input, e)
+    })
+}
+
+/// Reads `input`, and writes its mesh to `output`,
+/// returning the number of triangles written.
+/// Either can be "-" for standard input or output.
+pub fn convert_file(
+    input: &str,
+    output: &str,
+    settings: &Settings,
+    format: OutputFormat,
+    options: &ExportOptions)
+-> Result<usize, CliError> {
+    let heightmap = load(input, settings)?;
+    let source = if input == STDIO_PATH { None } else { Some(input) };
+    let result = if output == STDIO_PATH {
+        export_stdout(&heightmap, source, Some(settings), format, options)
+    } else {
+        export(&heightmap, source, Some(settings), output, format, options)
+    };
+    result.map_err(|e| CliError {
+            code: EXIT_OUTPUT_ERROR,
+            message: format!("Could not write {}: {}", output, e)
+        })
+}
+
+/// Reads `input`, and writes its mesh split into tiles,
+/// returning the files written and the number of triangles in each.
+pub fn convert_tiles(
+    input: &str,
+    output: &str,
+    settings: &Settings,
+    format: OutputFormat,
+    options: &ExportOptions,
+    tile_options: &TileOptions)
+-> Result<Vec<(String, usize)>, CliError> {
+    if output == STDIO_PATH {
+        return Err(CliError {
+            code: 2,
+            message: "Tiles need an output file name, not -".to_string()
+        });
+    }
+    let heightmap = load(input, settings)?;
+    let source = if input == STDIO_PATH { None } else { Some(input) };
+    export_tiles(&heightmap, source, Some(settings), output, format, options, tile_options)
+        .map_err(|e| CliError {
+            code: EXIT_OUTPUT_ERROR,
+            message: format!("Could not write tiles of {}: {}", output, e)
+        })
+}
+
+pub fn convert(args: &ConvertArgs) -> Result<(), CliError> {
+    if args.watch {
+        return watch(args);
+    }
+    let written = convert_once(args)?;
+    if args.tiles.to_options().is_some() {
+        for (path, triangle_count) in written {
+            eprintln!("Wrote {} ({} triangles)", path, triangle_count);
+        }
+    }
+    Ok(())
+}
+
+/// Converts, returning the files written and the number of triangles in each
+fn convert_once(args: &ConvertArgs) -> Result<Vec<(String, usize)>, CliError> {
+    let preset = resolve_preset(&args.settings, &args.export)?;
+    if let Some(path) = &args.save_preset {
+        preset.write(path).map_err(|e| CliError {
+            code: EXIT_OUTPUT_ERROR,
+            message: format!("Could not write preset {}: {}", path, e)
+        })?;
+    }
+    let format = args.export.output_format(&args.output);
+    match args.tiles.to_options() {
+        Some(tile_options) => convert_tiles(&args.input, &args.output,
+            &preset.settings, format, &preset.export, &tile_options),
+        None => convert_file(&args.input, &args.output, &preset.settings, format, &preset.export)
+            .map(|triangle_count| vec![(args.output.clone(), triangle_count)])
+    }
+}
+
+/// Converts, then converts again whenever the input or preset file changes.
+/// Runs until interrupted; errors are printed without stopping.
+fn watch(args: &ConvertArgs) -> Result<(), CliError> {
+    if args.input == STDIO_PATH || args.output == STDIO_PATH {
+        return Err(CliError {
+            code: 2,
+            message: "--watch needs input and output files, not -".to_string()
+        });
+    }
+    let mut watchers = vec![FileWatcher::new(&args.input)];
+    if let Some(preset) = &args.settings.preset {
+        watchers.push(FileWatcher::new(preset));
+    }
+    eprintln!("Watching {} for changes (Ctrl+C to stop)", args.input);
+    loop {
+        match convert_once(args) {
+            Ok(written) => for (path, triangle_count) in written {
+                eprintln!("Wrote {} ({} triangles)", path, triangle_count);
+            },
+            Err(error) => eprintln!("{}", error.message)
+        }
+        let mut changed = false;
+        while !changed {
+            thread::sleep(POLL_INTERVAL);
+            // Every watcher is polled, so none is left with a stale change
+            for watcher in &mut watchers {
+                changed |= watcher.poll();
+            }
+        }
+    }
+}
+
+pub fn lithophane(args: &LithophaneArgs) -> Result<(), CliError> {
+    let options = LithophaneOptions {
+        shape: match args.shape {
+            ShapeArg::Flat => LithophaneShape::Flat,
+            ShapeArg::Curved => LithophaneShape::Curved,
+            ShapeArg::Cylinder => LithophaneShape::Cylinder
+        },
+        width: args.width,
+        min_thickness: args.min_thickness,
+        max_thickness: args.max_thickness,
+        frame: args.frame,
+        stand: args.stand,
+        angle: args.angle
+    };
+    options.validate().map_err(|message| CliError {
+        code: 2,
+        message: format!("Invalid lithophane: {}", message)
+    })?;
+    let image_options = lithophane_image_options();
+    let result = if args.input == STDIO_PATH {
+        let mut bytes = Vec::<u8>::new();
+        std::io::stdin().lock().read_to_end(&mut bytes)
+            .map_err(ReadError::from)
+            .and_then(|_| read_heightmap_image_bytes(&bytes, &image_options))
+    } else {
+        read_heightmap_image(&args.input, &image_options)
+    };
+    let mut image = result.map_err(|e| CliError {
+        code: EXIT_INPUT_ERROR,
+        message: format!("Could not read image {}: {}", args.input, e)
+    })?;
+    if let Some(unit) = args.unit {
+        image.unit = unit.to_unit();
+    }
+    let heightmap = lithophane_heightmap(&image, &options);
+    let mut export_options = ExportOptions::default();
+    args.export.apply(&mut export_options);
+    // Unless the shape is flat, so that --wrap can bend it instead
+    if let Some(wrap) = options.wrap() {
+        export_options.wrap = Some(wrap);
+    }
+    let format = args.export.output_format(&args.output);
+    let source = if args.input == STDIO_PATH { None } else { Some(args.input.as_str()) };
+    let result = if args.output == STDIO_PATH {
+        export_stdout(&heightmap, source, None, format, &export_options)
+    } else {
+        export(&heightmap, source, None, &args.output, format, &export_options)
+    };
+    result.map(|_| ()).map_err(|e| CliError {
+        code: EXIT_OUTPUT_ERROR,
+        message: format!("Could not write {}: {}", args.output, e)
+    })
+}
+
+pub fn contours(args: &ContoursArgs) -> Result<(), CliError> {
+    let heightmap = load(&args.input, &args.settings.to_settings()?)?;
+    let levels = match args.interval {
+        Some(interval) => ContourLevels::Interval(interval),
+        None => ContourLevels::Heights(args.levels.clone())
+    };
+    let contours = extract_contours(&heightmap, &levels);
+    let format = args.format.unwrap_or(if args.output.to_lowercase().ends_with(".dxf") {
+        ContourFormatArg::Dxf
+    } else {
+        ContourFormatArg::Svg
+    });
+    let footprint = Vec2::new(heightmap.size.x as f32, heightmap.size.y as f32).component_mul(&heightmap.scale);
+    let write = |output: &mut dyn Write| match format {
+        ContourFormatArg::Svg => write_contours_svg(output, &contours, footprint, heightmap.unit),
+        ContourFormatArg::Dxf => write_contours_dxf(output, &contours)
+    };
+    let result = if args.output == STDIO_PATH {
+        write(&mut std::io::stdout().lock())
+    } else {
+        File::create(&args.output).and_then(|file| {
+            let mut output = BufWriter::new(file);
+            write(&mut output)?;
+            output.flush()
+        })
+    };
+    result.map_err(|e| CliError {
+        code: EXIT_OUTPUT_ERROR,
+        message: format!("Could not write {}: {}", args.output, e)
+    })
+}
+
+/// Formats a file size in bytes, KB or MB
+pub fn format_size(bytes: u64) -> String {
+    if bytes >= 1 << 20 {
+        format!("{:.1} MB", bytes as f64 / (1 << 20) as f64)
+    } else if bytes >= 1 << 10 {
+        format!("{:.1} KB", bytes as f64 / (1 << 10) as f64)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+pub fn info(args: &InfoArgs) -> Result<(), CliError> {
+    let heightmap = load(&args.input, &args.settings.to_settings()?)?;
+    let stats = heightmap_stats(&heightmap);
+    let mut stdout = std::io::stdout().lock();
+    let result = if args.json {
+        let json = serde_json::to_string_pretty(&stats).map_err(|e| CliError {
+            code: EXIT_OUTPUT_ERROR,
+            message: e.to_string()
+        })?;
+        writeln!(stdout, "{}", json)
+    } else {
+        write_info(&mut stdout, &stats)
+    };
+    match result {
+        // Whatever reads the output (such as `head`) has stopped before the end of it
+        Err(error) if error.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.map_err(|e| CliError {
+            code: EXIT_OUTPUT_ERROR,
+            message: format!("Could not write to standard output: {}", e)
+        })
+    }
+}
+
+fn write_info<W: Write>(output: &mut W, stats: &HeightmapStats) -> Result<(), std::io::Error> {
+    let mesh = &stats.mesh;
+    writeln!(output, "size:         {} x {} samples", stats.size.x, stats.size.y)?;
+    let unit = stats.unit.abbreviation();
+    writeln!(output, "scale:        {} x {} {}", stats.scale.x, stats.scale.y, unit)?;
+    match (stats.min_height, stats.max_height, stats.mean_height) {
+        (Some(min), Some(max), Some(mean)) =>
+            writeln!(output, "heights:      {} to {} {} (mean {})", min, max, unit, mean)?,
+        _ => writeln!(output, "heights:      n/a (every sample is NaN)")?
+    }
+    writeln!(output, "holes:        {} ({} NaN)", stats.hole_count, stats.nan_count)?;
+    writeln!(output, "triangles:    {}", mesh.triangle_count)?;
+    if let Some((min, max)) = mesh.bounds {
+        writeln!(output, "bounds:       {}, {}, {} to {}, {}, {}", min.x, min.y, min.z, max.x, max.y, max.z)?;
+        let size = max - min;
+        writeln!(output, "mesh size:    {} x {} x {} {}", size.x, size.y, size.z, unit)?;
+    }
+    writeln!(output, "surface area: {:.3} {}²", mesh.surface_area, unit)?;
+    writeln!(output, "volume:       {:.3} {}³", mesh.volume, unit)?;
+    writeln!(output, "STL size:     {} ({} bytes)", format_size(mesh.stl_size), mesh.stl_size)
+}
+
+/// Prints the error (if any) of a command, and gives its exit code
+pub fn report(result: Result<(), CliError>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error.message);
+            ExitCode::from(error.code)
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Seek, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::geometry::heightmap::*;
+use crate::geometry::threemf::*;
+use crate::geometry::ply::*;
+use crate::geometry::gltf::*;
+use crate::geometry::tiles::*;
+use crate::geometry::wrap::Wrap;
+use crate::geometry::hollow::Hollow;
+use crate::geometry::outline::*;
+use crate::geometry::triangle::{StlWriter, Triangle};
+use crate::settings::Settings;
+
+/// Colors used for height bands: water, grass, earth, snow
+const TERRAIN_COLORS: [[u8; 3]; 4] = [[40, 90, 200], [60, 150, 60], [140, 110, 70], [240, 240, 240]];
+
+/// Where STL facet colors come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StlColors {
+    None,
+    Image,
+    HeightBands
+}
+
+/// Mesh file formats that can be exported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Stl,
+    ThreeMf,
+    Ply,
+    Glb
+}
+
+impl OutputFormat {
+    /// Format for a file name: `.3mf` for 3MF, `.ply` for PLY,
+    /// `.glb` for binary glTF, otherwise binary STL.
+    pub fn from_path(path: &str) -> Self {
+        let extension = Path::new(path).extension()
+            .map_or(String::new(), |extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_str() {
+            "3mf" => Self::ThreeMf,
+            "ply" => Self::Ply,
+            "glb" => Self::Glb,
+            _ => Self::Stl
+        }
+    }
+}
+
+/// Settings for formats that support more than plain geometry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ExportOptions {
+    /// Color of walls in formats with vertex colors
+    pub wall_color: [u8; 3],
+    /// Color of the base in formats with vertex colors
+    pub base_color: [u8; 3],
+    pub ply_format: PlyFormat,
+    /// Include vertex colors in GLB files
+    pub glb_vertex_colors: bool,
+    /// Embed the source image as a texture in GLB files
+    pub glb_texture: bool,
+    /// Colors stored in STL attribute bytes
+    pub stl_colors: StlColors,
+    /// Trace the outline of masks and holes smoothly between samples,
+    /// rather than along their edges
+    pub smooth_outline: bool,
+    // Last, as these are tables in preset files
+    /// Bend the solid around a cylinder, sphere or curve, rather than leaving it flat
+    pub wrap: Option<Wrap>,
+    /// Hollow the solid out, rather than leaving it solid
+    pub hollow: Option<Hollow>
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            wall_color: [160, 160, 160],
+            base_color: [160, 160, 160],
+            ply_format: PlyFormat::BinaryLittleEndian,
+            glb_vertex_colors: true,
+            glb_texture: false,
+            stl_colors: StlColors::None,
+            smooth_outline: false,
+            wrap: None,
+            hollow: None
+        }
+    }
+}
+
+/// Today's date (UTC) as YYYY-MM-DD
+fn utc_date() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    // Converts days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Writes the heightmap's mesh to `output_file` in the given format,
+/// returning the number of triangles written.
+///
+/// `source` is the file the heightmap was loaded from, and `settings` how it was loaded,
+/// both recorded by formats with metadata.
+pub fn export(
+    heightmap: &Heightmap,
+    source: Option<&str>,
+    settings: Option<&Settings>,
+    output_file: &str,
+    format: OutputFormat,
+    options: &ExportOptions)
+-> Result<usize, std::io::Error> {
+    let path = Path::new(output_file);
+    let name = path.file_stem()
+        .map_or("heightmap".to_string(), |stem| stem.to_string_lossy().to_string());
+    let colors = facet_colors(heightmap, options);
+    write_mesh(heightmap, source, settings, &name, &mut File::create(path)?, format, options, &colors)
+}
+
+/// File name for a tile: `output_file` with the tile's row and column (from 1)
+/// added to its stem, such as `city_r1_c2.stl`
+pub fn tile_path(output_file: &str, tile: &Tile) -> String {
+    let path = Path::new(output_file);
+    let stem = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+    let name = match path.extension() {
+        Some(extension) => format!("{}_r{}_c{}.{}",
+            stem, tile.row + 1, tile.column + 1, extension.to_string_lossy()),
+        None => format!("{}_r{}_c{}", stem, tile.row + 1, tile.column + 1)
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+/// Splits the heightmap into tiles, and writes each one's mesh
+/// to its own file (named by `tile_path`) like `export`.
+///
+/// Returns the file names written, and the number of triangles in each.
+pub fn export_tiles(
+    heightmap: &Heightmap,
+    source: Option<&str>,
+    settings: Option<&Settings>,
+    output_file: &str,
+    format: OutputFormat,
+    options: &ExportOptions,
+    tile_options: &TileOptions)
+-> Result<Vec<(String, usize)>, std::io::Error> {
+    let tiles = split_tiles(heightmap, tile_options)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // Height bands are set by the whole map, so that they match across tiles
+    let colors = facet_colors(heightmap, options);
+    let mut written = Vec::new();
+    for tile in &tiles {
+        let path = tile_path(output_file, tile);
+        let name = Path::new(&path).file_stem()
+            .map_or("heightmap".to_string(), |stem| stem.to_string_lossy().to_string());
+        let triangle_count = write_mesh(&tile.heightmap, source, settings, &name, &mut File::create(&path)?,
+            format, options, &colors)?;
+        written.push((path, triangle_count));
+    }
+    Ok(written)
+}
+
+/// Writes the heightmap's mesh to standard output, like `export`.
+///
+/// 3MF files are finished by seeking back to earlier parts of them,
+/// so they are built in memory first. STL files are meshed twice instead,
+/// first to count the triangles that go in the header.
+pub fn export_stdout(
+    heightmap: &Heightmap,
+    source: Option<&str>,
+    settings: Option<&Settings>,
+    format: OutputFormat,
+    options: &ExportOptions)
+-> Result<usize, std::io::Error> {
+    let colors = facet_colors(heightmap, options);
+    let mut stdout = std::io::stdout().lock();
+    let triangle_count = match format {
+        OutputFormat::ThreeMf => {
+            let mut buffer = Cursor::new(Vec::<u8>::new());
+            let triangle_count = write_mesh(heightmap, source, settings, "heightmap", &mut buffer,
+                format, options, &colors)?;
+            stdout.write_all(buffer.get_ref())?;
+            triangle_count
+        },
+        OutputFormat::Stl => {
+            let mesher = Mesher::new(options)?;
+            // Never finished, so the count it is given doesn't matter
+            let mut counter = StlWriter::with_triangle_count(std::io::sink(), "", 0)?;
+            write_stl_facets(heightmap, mesher, &mut counter, &colors)?;
+            let triangle_count = counter.triangle_count();
+            let mut writer = StlWriter::with_triangle_count(&mut stdout, &stl_header_text(heightmap, source),
+                triangle_count)?;
+            write_stl_facets(heightmap, mesher, &mut writer, &colors)?;
+            writer.finish_streamed()?;
+            triangle_count as usize
+        },
+        OutputFormat::Ply | OutputFormat::Glb => write_colored_mesh(heightmap, &mut stdout, format, options)?
+    };
+    stdout.flush()?;
+    Ok(triangle_count)
+}
+
+/// STL facet colors for the heightmap
+fn facet_colors(heightmap: &Heightmap, options: &ExportOptions) -> FacetColors {
+    match options.stl_colors {
+        StlColors::None => FacetColors::None,
+        StlColors::Image => FacetColors::Image { other: options.wall_color },
+        StlColors::HeightBands => FacetColors::even_bands(heightmap, &TERRAIN_COLORS)
+    }
+}
+
+/// Which of the ways of meshing a heightmap the export options ask for
+#[derive(Clone, Copy)]
+enum Mesher<'a> {
+    Flat,
+    Smooth,
+    Wrap(&'a Wrap),
+    Hollow(&'a Hollow)
+}
+
+impl<'a> Mesher<'a> {
+    /// Checks that the options don't ask for more than one
+    fn new(options: &'a ExportOptions) -> Result<Self, std::io::Error> {
+        let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+        if let Some(wrap) = &options.wrap {
+            wrap.validate().map_err(|message| invalid(&message))?;
+        }
+        match (&options.wrap, &options.hollow, options.smooth_outline) {
+            (None, None, false) => Ok(Self::Flat),
+            (None, None, true) => Ok(Self::Smooth),
+            (Some(wrap), None, false) => Ok(Self::Wrap(wrap)),
+            (None, Some(hollow), false) => Ok(Self::Hollow(hollow)),
+            (Some(_), Some(_), _) => Err(invalid("A mesh can't be both wrapped and hollowed")),
+            (_, _, true) => Err(invalid("Wrapped and hollowed meshes can't have a smooth outline"))
+        }
+    }
+
+    /// Meshes the heightmap
+    fn get_triangles(self, heightmap: &Heightmap) -> Vec<Triangle> {
+        match self {
+            Self::Flat => heightmap.get_triangles(),
+            Self::Smooth => get_smooth_triangles(heightmap),
+            Self::Wrap(wrap) => wrap.get_triangles(heightmap),
+            Self::Hollow(hollow) => hollow.get_triangles(heightmap)
+        }
+    }
+}
+
+/// The heightmap's triangles, meshed the way the export options ask for
+pub fn mesh_triangles(heightmap: &Heightmap, options: &ExportOptions) -> Result<Vec<Triangle>, std::io::Error> {
+    Ok(Mesher::new(options)?.get_triangles(heightmap))
+}
+
+/// Writes the heightmap's mesh, with `name` as the model name in formats that have one
+#[allow(clippy::too_many_arguments)]
+fn write_mesh<W: Write + Seek>(
+    heightmap: &Heightmap,
+    source: Option<&str>,
+    settings: Option<&Settings>,
+    name: &str,
+    output: &mut W,
+    format: OutputFormat,
+    options: &ExportOptions,
+    colors: &FacetColors)
+-> Result<usize, std::io::Error> {
+    let mesher = Mesher::new(options)?;
+    match format {
+        OutputFormat::Ply | OutputFormat::Glb => write_colored_mesh(heightmap, output, format, options),
+        OutputFormat::ThreeMf => {
+            let triangles = mesher.get_triangles(heightmap);
+            let mut threemf_options = ThreeMfOptions::for_heightmap(name, source, heightmap);
+            if let Some(settings) = settings {
+                add_parameters(&mut threemf_options.parameters, "settings", &settings);
+            }
+            add_parameters(&mut threemf_options.parameters, "export", options);
+            write_3mf(output, &triangles, &threemf_options)?;
+            Ok(triangles.len())
+        },
+        OutputFormat::Stl => {
+            let mut writer = StlWriter::with_header(output, &stl_header_text(heightmap, source))?;
+            write_stl_facets(heightmap, mesher, &mut writer, colors)?;
+            let triangle_count = writer.triangle_count();
+            writer.finish()?;
+            Ok(triangle_count as usize)
+        }
+    }
+}
+
+/// Adds each of `value`'s settings to `parameters`, keyed by where it would be
+/// in a preset file (such as `settings.z-scale` or `export.wrap.shape`),
+/// so that the export can be reproduced
+fn add_parameters<T: Serialize>(parameters: &mut Vec<(String, String)>, key: &str, value: &T) {
+    match toml::Value::try_from(value) {
+        Ok(toml::Value::Table(table)) => for (name, value) in table {
+            add_parameters(parameters, &format!("{}.{}", key, name), &value);
+        },
+        Ok(toml::Value::String(text)) => parameters.push((key.to_string(), text)),
+        Ok(value) => parameters.push((key.to_string(), value.to_string())),
+        // Settings that can't be written as TOML can't be saved in a preset either
+        Err(_) => ()
+    }
+}
+
+/// Writes the heightmap's mesh in a format with vertex colors (PLY or GLB)
+fn write_colored_mesh<W: Write>(
+    heightmap: &Heightmap,
+    output: W,
+    format: OutputFormat,
+    options: &ExportOptions)
+-> Result<usize, std::io::Error> {
+    let mesher = Mesher::new(options)?;
+    let triangles = match mesher {
+        Mesher::Flat => heightmap.get_colored_triangles(options.wall_color, options.base_color),
+        Mesher::Smooth => get_smooth_colored_triangles(heightmap, options.wall_color, options.base_color),
+        Mesher::Wrap(wrap) => wrap.get_colored_triangles(heightmap, options.wall_color, options.base_color),
+        Mesher::Hollow(hollow) => hollow.get_colored_triangles(heightmap, options.wall_color, options.base_color)
+    };
+    if format == OutputFormat::Glb {
+        let mut texture = if options.glb_texture { GlbTexture::from_heightmap(heightmap) } else { None };
+        if let (Some(texture), Mesher::Wrap(wrap)) = (&mut texture, mesher) {
+            // Draped over the heightmap before it was wrapped
+            texture.flat_triangles = Some(wrap.get_flat_triangles(heightmap));
+        }
+        let glb_options = GlbOptions {
+            unit: heightmap.unit,
+            vertex_colors: options.glb_vertex_colors,
+            texture
+        };
+        write_glb(BufWriter::new(output), &triangles, &glb_options)?;
+    } else {
+        write_ply(output, &triangles, options.ply_format)?;
+    }
+    Ok(triangles.len())
+}
+
+/// Header text of binary STL files: the program, source file, scale and date
+fn stl_header_text(heightmap: &Heightmap, source: Option<&str>) -> String {
+    let mut parts = vec!["heightmap_to_stl".to_string()];
+    if let Some(name) = source.and_then(|s| Path::new(s).file_name()) {
+        parts.push(name.to_string_lossy().to_string());
+    }
+    parts.push(format!("scale {}x{} {}", heightmap.scale.x, heightmap.scale.y, heightmap.unit.abbreviation()));
+    parts.push(utc_date());
+    parts.join(" ")
+}
+
+/// Meshes the heightmap into a binary STL, with the mesher the export options ask for
+fn write_stl_facets<W: Write>(heightmap: &Heightmap, mesher: Mesher, writer: &mut StlWriter<W>, colors: &FacetColors)
+-> Result<(), std::io::Error> {
+    match mesher {
+        Mesher::Flat => heightmap.write_stl(writer, colors),
+        Mesher::Smooth => write_smooth_stl(heightmap, writer, colors),
+        Mesher::Wrap(wrap) => wrap.write_stl(heightmap, writer, colors),
+        Mesher::Hollow(hollow) => hollow.write_stl(heightmap, writer, colors)
+    }
+}
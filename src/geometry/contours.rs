@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::io::Write;
+extern crate nalgebra_glm as glm;
+use glm::Vec2;
+use crate::geometry::heightmap::Heightmap;
+use crate::geometry::units::Unit;
+
+/// Heights to draw contours at
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContourLevels {
+    /// Every multiple of this height from 0, below the highest sample
+    Interval(f32),
+    /// These heights
+    Heights(Vec<f32>)
+}
+
+/// The outlines of the parts of a heightmap higher than `level`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contour {
+    pub level: f32,
+    /// Closed polylines in model coordinates (the last point joins the first),
+    /// counterclockwise around the parts higher than `level`, and clockwise around holes in them
+    pub polylines: Vec<Vec<Vec2>>
+}
+
+/// Side of a cell between sample centers that a contour crosses,
+/// as the samples at its ends, in row order
+type Side = ((i32, i32), (i32, i32));
+
+fn side(a: (i32, i32), b: (i32, i32)) -> Side {
+    if (a.1, a.0) < (b.1, b.0) { (a, b) } else { (b, a) }
+}
+
+impl ContourLevels {
+    /// The heights, from lowest to highest
+    pub fn heights(&self, heightmap: &Heightmap) -> Vec<f32> {
+        let mut heights = match self {
+            Self::Interval(interval) => {
+                let max = heightmap.samples.iter().copied().fold(0., f32::max);
+                if *interval > 0. {
+                    (0..).map(|k| k as f32 * interval).take_while(|height| *height < max).collect()
+                } else {
+                    Vec::new()
+                }
+            },
+            Self::Heights(heights) => heights.clone()
+        };
+        heights.sort_by(f32::total_cmp);
+        heights.dedup();
+        heights
+    }
+}
+
+/// Removes the points of a closed polyline that are on a straight line
+/// between the ones before and after them
+fn remove_collinear(points: Vec<Vec2>) -> Vec<Vec2> {
+    let collinear = |a: &Vec2, b: &Vec2, c: &Vec2| (b.x - a.x) * (c.y - b.y) == (b.y - a.y) * (c.x - b.x);
+    let mut result: Vec<Vec2> = Vec::with_capacity(points.len());
+    for point in points {
+        while result.len() >= 2 && collinear(&result[result.len() - 2], &result[result.len() - 1], &point) {
+            result.pop();
+        }
+        result.push(point);
+    }
+    // Where the polyline closes
+    while result.len() > 3 && collinear(&result[result.len() - 2], &result[result.len() - 1], &result[0]) {
+        result.pop();
+    }
+    while result.len() > 3 && collinear(&result[result.len() - 1], &result[0], &result[1]) {
+        result.remove(0);
+    }
+    result
+}
+
+/// Traces the outlines of the parts of the heightmap higher than `level`,
+/// with marching squares through the cells between sample centers.
+/// Outside the map counts as below every level, so outlines close along its edges.
+fn trace(heightmap: &Heightmap, level: f32) -> Vec<Vec<Vec2>> {
+    let size = heightmap.size;
+    let in_map = |(i, j): (i32, i32)| i >= 0 && j >= 0 && i < size.x && j < size.y;
+    let value = |sample: (i32, i32)| {
+        if in_map(sample) { heightmap.sample(sample.0, sample.1) } else { f32::NEG_INFINITY }
+    };
+    let center = |(i, j): (i32, i32)| Vec2::new(i as f32 + 0.5, j as f32 + 0.5);
+    // Where a contour crosses each side, in samples
+    let point = |(a, b): Side| {
+        if in_map(a) && in_map(b) {
+            let (value_a, value_b) = (value(a), value(b));
+            center(a) + (center(b) - center(a)) * ((level - value_a) / (value_b - value_a))
+        } else {
+            // On the edge of the map
+            (center(a) + center(b)) / 2.
+        }
+    };
+    // Each contour segment, from the side it leaves a cell through (with the higher part
+    // on its left) to the side it leaves the next cell through, and the corner of the map
+    // it turns at in between, if any
+    let mut next = HashMap::<Side, (Side, Option<Vec2>)>::new();
+    let mut starts = Vec::new();
+    for j in -1..size.y {
+        for i in -1..size.x {
+            let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+            let inside = corners.map(|corner| value(corner) > level);
+            let crossed: Vec<usize> = (0..4).filter(|k| inside[*k] != inside[(k + 1) % 4]).collect();
+            let sides = |k: usize| side(corners[k], corners[(k + 1) % 4]);
+            // Opposite corners inside are only joined if the middle of the cell is inside too
+            let joined = crossed.len() == 4
+                && corners.iter().map(|corner| value(*corner)).sum::<f32>() / 4. > level;
+            // Cells at the corners of the map, which is in the middle of them
+            let map_corner = (i == -1 || i == size.x - 1) && (j == -1 || j == size.y - 1);
+            let turn = map_corner.then(|| Vec2::new((i + 1) as f32, (j + 1) as f32));
+            for &k in crossed.iter().filter(|k| inside[**k]) {
+                let entry = match crossed.len() {
+                    2 => *crossed.iter().find(|k| !inside[**k]).unwrap(),
+                    _ if joined => (k + 1) % 4,
+                    _ => (k + 3) % 4
+                };
+                next.insert(sides(k), (sides(entry), turn));
+                starts.push(sides(k));
+            }
+        }
+    }
+    let mut polylines = Vec::new();
+    for start in starts {
+        let mut points = Vec::new();
+        let mut current = start;
+        while let Some((following, turn)) = next.remove(&current) {
+            points.push(point(current));
+            points.extend(turn);
+            current = following;
+        }
+        if points.len() > 2 {
+            polylines.push(remove_collinear(points).iter()
+                .map(|p| p.component_mul(&heightmap.scale))
+                .collect());
+        }
+    }
+    polylines
+}
+
+/// Extracts the contours of the heightmap at each of the levels, from lowest to highest
+pub fn extract_contours(heightmap: &Heightmap, levels: &ContourLevels) -> Vec<Contour> {
+    levels.heights(heightmap).into_iter()
+        .map(|level| Contour { level, polylines: trace(heightmap, level) })
+        .collect()
+}
+
+/// Writes contours as an SVG drawing of `footprint` (the heightmap's size in model units),
+/// with one Inkscape layer of outlines per level, and y pointing down.
+/// The drawing's size is in millimeters.
+pub fn write_contours_svg<W: Write>(mut output: W, contours: &[Contour], footprint: Vec2, unit: Unit)
+-> Result<(), std::io::Error> {
+    let millimeters = unit.millimeters();
+    writeln!(output, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(output, concat!(r#"<svg xmlns="http://www.w3.org/2000/svg" "#,
+        r#"xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape" "#,
+        r#"width="{}mm" height="{}mm" viewBox="0 0 {} {}">"#),
+        footprint.x * millimeters, footprint.y * millimeters, footprint.x, footprint.y)?;
+    // A hairline for laser cutters: 0.1 mm
+    let stroke_width = 0.1 / millimeters;
+    for (index, contour) in contours.iter().enumerate() {
+        writeln!(output, r#"  <g id="level-{}" inkscape:groupmode="layer" inkscape:label="{}{}">"#,
+            index + 1, contour.level, unit.abbreviation())?;
+        for polyline in &contour.polylines {
+            write!(output, r#"    <path fill="none" stroke="black" stroke-width="{}" d=""#, stroke_width)?;
+            for (k, point) in polyline.iter().enumerate() {
+                write!(output, "{}{},{} ", if k == 0 { "M" } else { "L" }, point.x, footprint.y - point.y)?;
+            }
+            writeln!(output, r#"Z"/>"#)?;
+        }
+        writeln!(output, "  </g>")?;
+    }
+    writeln!(output, "</svg>")
+}
+
+/// Writes contours as a DXF (R12) drawing, with one layer of closed polylines per level,
+/// each at the elevation of its level.
+/// R12 has no header variable for units, so coordinates are in the heightmap's units.
+pub fn write_contours_dxf<W: Write>(mut output: W, contours: &[Contour])
+-> Result<(), std::io::Error> {
+    // Layer names can only have letters, digits, '-', '_' and '$'
+    let layer = |contour: &Contour| format!("LEVEL_{}", contour.level).replace(['.', '+'], "_");
+    let mut pair = |code: u16, value: &dyn std::fmt::Display| writeln!(output, "{}\n{}", code, value);
+    pair(0, &"SECTION")?;
+    pair(2, &"HEADER")?;
+    pair(9, &"$ACADVER")?;
+    pair(1, &"AC1009")?;
+    pair(0, &"ENDSEC")?;
+    pair(0, &"SECTION")?;
+    pair(2, &"TABLES")?;
+    // The solid line type the layers use
+    pair(0, &"TABLE")?;
+    pair(2, &"LTYPE")?;
+    pair(70, &1)?;
+    pair(0, &"LTYPE")?;
+    pair(2, &"CONTINUOUS")?;
+    pair(70, &0)?;
+    pair(3, &"Solid line")?;
+    pair(72, &65)?;
+    pair(73, &0)?;
+    pair(40, &0.0)?;
+    pair(0, &"ENDTAB")?;
+    pair(0, &"TABLE")?;
+    pair(2, &"LAYER")?;
+    pair(70, &contours.len())?;
+    for (index, contour) in contours.iter().enumerate() {
+        pair(0, &"LAYER")?;
+        pair(2, &layer(contour))?;
+        pair(70, &0)?;
+        // Cycles through the standard colors, red to white
+        pair(62, &(index % 7 + 1))?;
+        pair(6, &"CONTINUOUS")?;
+    }
+    pair(0, &"ENDTAB")?;
+    pair(0, &"ENDSEC")?;
+    pair(0, &"SECTION")?;
+    pair(2, &"ENTITIES")?;
+    for contour in contours {
+        let layer = layer(contour);
+        for polyline in &contour.polylines {
+            pair(0, &"POLYLINE")?;
+            pair(8, &layer)?;
+            pair(66, &1)?;
+            pair(10, &0)?;
+            pair(20, &0)?;
+            pair(30, &contour.level)?;
+            // Closed
+            pair(70, &1)?;
+            for point in polyline {
+                pair(0, &"VERTEX")?;
+                pair(8, &layer)?;
+                pair(10, &point.x)?;
+                pair(20, &point.y)?;
+                pair(30, &contour.level)?;
+            }
+            pair(0, &"SEQEND")?;
+            pair(8, &layer)?;
+        }
+    }
+    pair(0, &"ENDSEC")?;
+    pair(0, &"EOF")
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::io::Write;
+extern crate nalgebra_glm as glm;
+use glm::{Vec2, Vec3};
+#[cfg(feature = "image-formats")]
+use image::{ColorType, ImageEncoder};
+#[cfg(feature = "image-formats")]
+use image::codecs::png::PngEncoder;
+use crate::geometry::triangle::*;
+use crate::geometry::heightmap::Heightmap;
+use crate::geometry::units::Unit;
+
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const CHUNK_JSON: u32 = 0x4E4F_534A;
+const CHUNK_BIN: u32 = 0x004E_4942;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+
+/// An image draped over the XY plane of the model
+pub struct GlbTexture {
+    /// PNG encoded image
+    pub png: Vec<u8>,
+    /// Model-space size covered by the image, starting at the origin
+    pub extent: Vec2,
+    /// If true, the first image row is at the largest y
+    /// (as with `Heightmap::invert_y`)
+    pub invert_y: bool,
+    /// Where each triangle's corners were in the XY plane, if they have been moved
+    /// out of it (as by `Wrap`), in the same order as the triangles
+    pub flat_triangles: Option<Vec<Triangle>>
+}
+
+impl GlbTexture {
+    /// Encodes the heightmap's colors as a texture covering its footprint.
+    ///
+    /// Returns None if the heightmap has no colors
+    /// (or there's no PNG encoder, without the image-formats feature).
+    #[cfg(feature = "image-formats")]
+    pub fn from_heightmap(heightmap: &Heightmap) -> Option<Self> {
+        let colors = heightmap.colors.as_ref()?;
+        let pixels: Vec<u8> = colors.iter().flatten().copied().collect();
+        let mut png = Vec::<u8>::new();
+        PngEncoder::new(&mut png).write_image(
+            &pixels,
+            heightmap.size.x as u32,
+            heightmap.size.y as u32,
+            ColorType::Rgb8).ok()?;
+        Some(Self {
+            png,
+            extent: Vec2::new(
+                heightmap.size.x as f32 * heightmap.scale.x,
+                heightmap.size.y as f32 * heightmap.scale.y),
+            invert_y: heightmap.invert_y,
+            flat_triangles: None
+        })
+    }
+
+    #[cfg(not(feature = "image-formats"))]
+    pub fn from_heightmap(_heightmap: &Heightmap) -> Option<Self> {
+        None
+    }
+
+    /// Texture coordinates of `position` in the XY plane
+    fn texcoord(&self, position: &Vec3) -> Vec2 {
+        let u = position.x / self.extent.x;
+        let v = position.y / self.extent.y;
+        Vec2::new(u, if self.invert_y { 1. - v } else { v })
+    }
+}
+
+#[derive(Default)]
+pub struct GlbOptions {
+    /// Unit of the model coordinates (glTF files are in meters)
+    pub unit: Unit,
+    /// Include the triangle colors as vertex colors
+    pub vertex_colors: bool,
+    /// Embed a texture, with texture coordinates for every vertex
+    pub texture: Option<GlbTexture>
+}
+
+/// Converts an 8 bit sRGB channel to the linear values glTF expects
+fn srgb_to_linear(channel: u8) -> f32 {
+    let c = channel as f32 / 255.;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Flat shaded, indexed vertex data
+#[derive(Default)]
+struct VertexData {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    colors: Vec<[u8; 3]>,
+    /// Texture coordinates, if there is a texture
+    texcoords: Vec<Vec2>,
+    indices: Vec<u32>
+}
+
+/// Merges vertices that share a position, face normal, color and texture coordinates.
+/// Triangles with no area are dropped.
+fn index_vertices(triangles: &[ColoredTriangle], texture: Option<&GlbTexture>) -> VertexData {
+    let mut data = VertexData::default();
+    let mut lookup = HashMap::<([u32; 8], [u8; 3]), u32>::new();
+    for (t, (triangle, color)) in triangles.iter().enumerate() {
+        let cross_product = glm::cross(&(triangle[1] - triangle[0]), &(triangle[2] - triangle[0]));
+        if glm::dot(&cross_product, &cross_product) <= 0.0 {
+            continue;
+        }
+        let normal = cross_product.normalize();
+        for (corner, vertex) in triangle.iter().enumerate() {
+            let texcoord = texture.map_or(Vec2::zeros(), |texture| {
+                let flat = texture.flat_triangles.as_ref().map_or(vertex, |flat| &flat[t][corner]);
+                texture.texcoord(flat)
+            });
+            let key = ([
+                (vertex.x + 0.).to_bits(), (vertex.y + 0.).to_bits(), (vertex.z + 0.).to_bits(),
+                (normal.x + 0.).to_bits(), (normal.y + 0.).to_bits(), (normal.z + 0.).to_bits(),
+                (texcoord.x + 0.).to_bits(), (texcoord.y + 0.).to_bits()],
+                *color);
+            let index = *lookup.entry(key).or_insert_with(|| {
+                data.positions.push(*vertex);
+                data.normals.push(normal);
+                data.colors.push(*color);
+                if texture.is_some() {
+                    data.texcoords.push(texcoord);
+                }
+                (data.positions.len() - 1) as u32
+            });
+            data.indices.push(index);
+        }
+    }
+    data
+}
+
+/// Binary buffer with the byte ranges (buffer views) stored in it
+#[derive(Default)]
+struct BinaryBuffer {
+    bytes: Vec<u8>,
+    views: Vec<String>
+}
+
+impl BinaryBuffer {
+    /// Appends a buffer view, returning its index
+    fn add_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        self.bytes.resize(self.bytes.len().next_multiple_of(4), 0);
+        let target = match target {
+            Some(target) => format!(r#","target":{}"#, target),
+            None => String::new()
+        };
+        self.views.push(format!(
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{}{}}}"#,
+            self.bytes.len(), data.len(), target));
+        self.bytes.extend_from_slice(data);
+        self.views.len() - 1
+    }
+
+    fn add_floats(&mut self, values: &[f32]) -> usize {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.add_view(&bytes, Some(ARRAY_BUFFER))
+    }
+}
+
+fn format_floats(values: &[f32]) -> String {
+    values.iter().map(|v| format!("{}", v)).collect::<Vec<_>>().join(",")
+}
+
+fn write_chunk<W: Write>(output: &mut W, chunk_type: u32, data: &[u8], padding: u8)
+-> Result<(), std::io::Error> {
+    let padded_length = data.len().next_multiple_of(4);
+    output.write_all(&(padded_length as u32).to_le_bytes())?;
+    output.write_all(&chunk_type.to_le_bytes())?;
+    output.write_all(data)?;
+    for _ in data.len()..padded_length {
+        output.write_all(&[padding])?;
+    }
+    Ok(())
+}
+
+/// Writes colored triangles to a binary glTF (GLB) file.
+///
+/// The mesh is flat shaded and indexed, with normals,
+/// and optionally vertex colors and a texture.
+/// The model's z axis is rotated to glTF's y (up) axis,
+/// and it is scaled from `options.unit` to meters.
+pub fn write_glb<W: Write>(
+    mut output: W,
+    triangles: &[ColoredTriangle],
+    options: &GlbOptions)
+-> Result<(), std::io::Error> {
+    let data = index_vertices(triangles, options.texture.as_ref());
+    let mut buffer = BinaryBuffer::default();
+    let mut accessors = Vec::<String>::new();
+    let mut attributes = Vec::<String>::new();
+
+    let mut min = Vec3::repeat(f32::INFINITY);
+    let mut max = Vec3::repeat(f32::NEG_INFINITY);
+    for position in &data.positions {
+        min = glm::min2(&min, position);
+        max = glm::max2(&max, position);
+    }
+    if data.positions.is_empty() {
+        min = Vec3::zeros();
+        max = Vec3::zeros();
+    }
+    let positions: Vec<f32> = data.positions.iter().flat_map(|p| [p.x, p.y, p.z]).collect();
+    let view = buffer.add_floats(&positions);
+    accessors.push(format!(
+        r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC3","min":[{}],"max":[{}]}}"#,
+        view, FLOAT, data.positions.len(),
+        format_floats(min.as_slice()), format_floats(max.as_slice())));
+    attributes.push(format!(r#""POSITION":{}"#, accessors.len() - 1));
+
+    let normals: Vec<f32> = data.normals.iter().flat_map(|n| [n.x, n.y, n.z]).collect();
+    let view = buffer.add_floats(&normals);
+    accessors.push(format!(
+        r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC3"}}"#,
+        view, FLOAT, data.normals.len()));
+    attributes.push(format!(r#""NORMAL":{}"#, accessors.len() - 1));
+
+    if options.vertex_colors {
+        let colors: Vec<f32> = data.colors.iter()
+            .flat_map(|c| c.map(srgb_to_linear))
+            .collect();
+        let view = buffer.add_floats(&colors);
+        accessors.push(format!(
+            r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC3"}}"#,
+            view, FLOAT, data.colors.len()));
+        attributes.push(format!(r#""COLOR_0":{}"#, accessors.len() - 1));
+    }
+
+    let mut material = r#"{"pbrMetallicRoughness":{"metallicFactor":0,"roughnessFactor":1}}"#
+        .to_string();
+    let mut texture_json = String::new();
+    if let Some(texture) = &options.texture {
+        let texcoords: Vec<f32> = data.texcoords.iter().flat_map(|t| [t.x, t.y]).collect();
+        let view = buffer.add_floats(&texcoords);
+        accessors.push(format!(
+            r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC2"}}"#,
+            view, FLOAT, data.positions.len()));
+        attributes.push(format!(r#""TEXCOORD_0":{}"#, accessors.len() - 1));
+
+        let image_view = buffer.add_view(&texture.png, None);
+        material = concat!(
+            r#"{"pbrMetallicRoughness":{"baseColorTexture":{"index":0},"#,
+            r#""metallicFactor":0,"roughnessFactor":1}}"#).to_string();
+        // Nearest filtering keeps each sample's color sharp, matching the flat top faces
+        texture_json = format!(concat!(
+            r#","images":[{{"bufferView":{},"mimeType":"image/png"}}]"#,
+            r#","samplers":[{{"magFilter":9728,"minFilter":9728,"wrapS":33071,"wrapT":33071}}]"#,
+            r#","textures":[{{"sampler":0,"source":0}}]"#),
+            image_view);
+    }
+
+    let index_bytes: Vec<u8> = data.indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+    let view = buffer.add_view(&index_bytes, Some(ELEMENT_ARRAY_BUFFER));
+    accessors.push(format!(
+        r#"{{"bufferView":{},"componentType":{},"count":{},"type":"SCALAR"}}"#,
+        view, UNSIGNED_INT, data.indices.len()));
+    let indices_accessor = accessors.len() - 1;
+
+    buffer.bytes.resize(buffer.bytes.len().next_multiple_of(4), 0);
+    let json = format!(
+        concat!(
+            r#"{{"asset":{{"version":"2.0","generator":"heightmap_to_stl"}},"#,
+            r#""scene":0,"scenes":[{{"nodes":[0]}}],"#,
+            r#""nodes":[{{"mesh":0,"rotation":[-0.70710677,0,0,0.70710677],"scale":[{scale},{scale},{scale}]}}],"#,
+            r#""meshes":[{{"primitives":[{{"attributes":{{{}}},"indices":{},"material":0}}]}}],"#,
+            r#""materials":[{}],"#,
+            r#""accessors":[{}],"#,
+            r#""bufferViews":[{}],"#,
+            r#""buffers":[{{"byteLength":{}}}]{}}}"#),
+        attributes.join(","), indices_accessor,
+        material,
+        accessors.join(","),
+        buffer.views.join(","),
+        buffer.bytes.len(), texture_json,
+        scale = options.unit.convert(1., Unit::Meter));
+
+    let json_length = json.len().next_multiple_of(4);
+    let total_length = 12 + 8 + json_length + 8 + buffer.bytes.len();
+    output.write_all(&GLB_MAGIC.to_le_bytes())?;
+    output.write_all(&2u32.to_le_bytes())?;
+    output.write_all(&(total_length as u32).to_le_bytes())?;
+    write_chunk(&mut output, CHUNK_JSON, json.as_bytes(), b' ')?;
+    write_chunk(&mut output, CHUNK_BIN, &buffer.bytes, 0)?;
+    output.flush()
+}
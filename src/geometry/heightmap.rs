@@ -1,132 +1,569 @@
+use std::convert::Infallible;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read, Write, Seek};
 extern crate nalgebra_glm as glm;
 use glm::{Vec2, Vec3, TVec2};
-use image::GenericImageView;
+#[cfg(feature = "image-formats")]
+use image::{DynamicImage, GenericImageView, Pixel};
 use crate::geometry::triangle::*;
 use crate::geometry::ReadError;
+use crate::geometry::units::Unit;
+#[cfg(feature = "image-formats")]
 use image::io::Reader as ImageReader;
+#[cfg(feature = "image-formats")]
 use rand::random;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 pub struct Heightmap {
     pub size: TVec2::<i32>,
     pub scale: Vec2,
     pub samples: Vec::<f32>,
-    pub invert_y: bool
+    /// RGB color of each sample, in the same order as `samples`
+    /// (only available for heightmaps loaded from images)
+    pub colors: Option<Vec::<[u8; 3]>>,
+    /// How much of each sample a mask covers, from 0 to 1, in the same order as `samples`.
+    /// Samples covered less than half are holes; the rest lets the outline be traced between samples.
+    pub coverage: Option<Vec::<f32>>,
+    pub invert_y: bool,
+    /// Unit of the scale and heights
+    pub unit: Unit
 }
 
-// Utility function to add a square face to a Vec of Triangles.
-fn add_rect(triangles: &mut Vec::<Triangle>, corners: [Vec3; 4]) {
-    triangles.push([corners[0], corners[1], corners[2]]);
-    triangles.push([corners[0], corners[2], corners[3]]);
+/// The part of the solid that a triangle belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Surface {
+    /// The upper face of the sample at (i, j)
+    Top(i32, i32),
+    /// A vertical face between samples, or along the edge of the map
+    Wall,
+    /// The lower face, at z = 0
+    Base
 }
 
+/// How to color the facets of a binary STL
+/// (stored in the attribute bytes, see `viscam_color`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum FacetColors {
+    /// Attribute bytes are left as zero
+    None,
+    /// Top faces take the color of their sample, other faces take `other`
+    Image { other: [u8; 3] },
+    /// Faces are colored by the height of their center.
+    /// Each band is (upper height, color), sorted by height,
+    /// and faces above the last band take the last color.
+    HeightBands(Vec<(f32, [u8; 3])>)
+}
+
+impl FacetColors {
+    /// Splits the heights of the heightmap evenly into one band per color
+    pub fn even_bands(heightmap: &Heightmap, colors: &[[u8; 3]]) -> Self {
+        let max = heightmap.samples.iter().copied().fold(0., f32::max);
+        let band_height = max / colors.len() as f32;
+        Self::HeightBands(colors.iter().enumerate()
+            .map(|(i, color)| ((i + 1) as f32 * band_height, *color))
+            .collect())
+    }
+
+    /// True if faces are colored by their sample, so top faces of different colors
+    /// mustn't be combined (see `Heightmap::for_each_triangle`)
+    pub fn by_sample(&self) -> bool {
+        matches!(self, Self::Image { .. })
+    }
+
+    /// Color of a triangle, if any
+    pub fn color(&self, heightmap: &Heightmap, triangle: &Triangle, surface: Surface)
+    -> Option<[u8; 3]> {
+        match self {
+            Self::None => None,
+            Self::Image { other } => match surface {
+                Surface::Top(i, j) => Some(heightmap.color(i, j).unwrap_or(*other)),
+                _ => Some(*other)
+            },
+            Self::HeightBands(bands) => {
+                let z = (triangle[0].z + triangle[1].z + triangle[2].z) / 3.;
+                bands.iter()
+                    .find(|(height, _)| z <= *height)
+                    .or(bands.last())
+                    .map(|(_, color)| *color)
+            }
+        }
+    }
+}
+
+// Utility function to emit a square face as two triangles.
+fn add_rect<E, F>(emit: &mut F, corners: [Vec3; 4], surface: Surface) -> Result<(), E>
+where F: FnMut(Triangle, Surface) -> Result<(), E> {
+    emit([corners[0], corners[1], corners[2]], surface)?;
+    emit([corners[0], corners[2], corners[3]], surface)
+}
+
+/// Rows meshed together by `fold_bands` (by one thread, with the `parallel` feature)
+const BAND_ROWS: usize = 16;
+
 impl Heightmap {
-    pub fn sample(&self, i: i32, j: i32) -> f32{
+    /// Index into `samples` (and `colors`) of the sample at (i, j)
+    fn index(&self, i: i32, j: i32) -> Option<usize> {
         if i < 0 || j < 0 || i >= self.size[0] || j >= self.size[1] {
-            0.
+            None
         } else if self.invert_y {
-            let index = ((self.size[1] - j - 1) * self.size[0] + i) as usize;
-            self.samples[index]
+            Some(((self.size[1] - j - 1) * self.size[0] + i) as usize)
         } else {
-            let index = (j * self.size[0] + i) as usize;
-            self.samples[index]
+            Some((j * self.size[0] + i) as usize)
+        }
+    }
+
+    /// Height of the sample at (i, j).
+    /// Samples outside the map, or that are NaN (missing), are 0.
+    pub fn sample(&self, i: i32, j: i32) -> f32{
+        match self.index(i, j) {
+            Some(index) if !self.samples[index].is_nan() => self.samples[index],
+            _ => 0.
+        }
+    }
+
+    /// Color of the sample at (i, j), if the heightmap has colors
+    pub fn color(&self, i: i32, j: i32) -> Option<[u8; 3]> {
+        let colors = self.colors.as_ref()?;
+        self.index(i, j).map(|index| colors[index])
+    }
+
+    /// How much of the sample at (i, j) a mask covers, if the heightmap has been masked
+    pub fn coverage(&self, i: i32, j: i32) -> Option<f32> {
+        let coverage = self.coverage.as_ref()?;
+        self.index(i, j).map(|index| coverage[index])
+    }
+
+    /// Color of a face on `surface`: its sample's color for top faces
+    /// (or `wall_color` if the heightmap has no colors)
+    pub fn surface_color(&self, surface: Surface, wall_color: [u8; 3], base_color: [u8; 3]) -> [u8; 3] {
+        match surface {
+            Surface::Top(i, j) => self.color(i, j).unwrap_or(wall_color),
+            Surface::Wall => wall_color,
+            Surface::Base => base_color
+        }
+    }
+
+    /// Multiplies every sample by `factor`
+    pub fn scale_heights(&mut self, factor: f32) {
+        for sample in &mut self.samples {
+            *sample *= factor;
+        }
+    }
+
+    /// Scales the footprint (keeping its proportions) to fit within `size`.
+    /// A zero width or depth doesn't constrain that direction.
+    pub fn fit_footprint(&mut self, size: Vec2) {
+        let footprint = Vec2::new(self.size.x as f32, self.size.y as f32).component_mul(&self.scale);
+        let factor = [0, 1].iter()
+            .filter(|&&axis| size[axis] > 0. && footprint[axis] > 0.)
+            .map(|&axis| size[axis] / footprint[axis])
+            .fold(f32::INFINITY, f32::min);
+        if factor.is_finite() {
+            self.scale *= factor;
+        }
+    }
+
+    /// Scales the heights so that the highest sample is `height` high
+    pub fn fit_relief(&mut self, height: f32) {
+        let max = self.samples.iter().copied().fold(0., f32::max);
+        if max > 0. {
+            self.scale_heights(height / max);
+        }
+    }
+
+    /// Rounds every sample to the nearest multiple of `step`, for a terraced model.
+    /// Samples above 0 are at least `step` high, so they don't become holes.
+    pub fn terrace(&mut self, step: f32) {
+        if step <= 0. {
+            return;
+        }
+        for sample in &mut self.samples {
+            if *sample > 0. {
+                *sample = (*sample / step).round().max(1.) * step;
+            }
+        }
+    }
+
+    /// Raises every sample by `thickness`, so that the model
+    /// sits on a solid base (and zero samples are no longer holes)
+    pub fn add_base(&mut self, thickness: f32) {
+        for sample in &mut self.samples {
+            *sample += thickness;
         }
     }
 
     pub fn get_triangles(&self)
     -> Vec::<Triangle> {
         let mut result = Vec::<Triangle>::new();
+        self.fold_bands(false,
+            Vec::<Triangle>::new,
+            |band, triangle, _surface| band.push(triangle),
+            |band| result.extend(band));
+        result
+    }
+
+    /// Triangles paired with a color.
+    ///
+    /// Top faces take the color of their sample (or `wall_color` if the
+    /// heightmap has no colors), walls take `wall_color`,
+    /// and the base takes `base_color`.
+    pub fn get_colored_triangles(&self, wall_color: [u8; 3], base_color: [u8; 3])
+    -> Vec::<ColoredTriangle> {
+        let mut result = Vec::<ColoredTriangle>::new();
+        self.fold_bands(true,
+            Vec::<ColoredTriangle>::new,
+            |band, triangle, surface| {
+                band.push((triangle, self.surface_color(surface, wall_color, base_color)));
+            },
+            |band| result.extend(band));
+        result
+    }
+
+    /// Meshes the heightmap straight into a binary STL, with the given facet colors.
+    ///
+    /// Triangles are written a band of rows at a time (encoded in parallel,
+    /// with the `parallel` feature), so memory use doesn't grow with the size of the heightmap.
+    pub fn write_stl<W: Write>(&self, writer: &mut StlWriter<W>, colors: &FacetColors)
+    -> Result<(), std::io::Error> {
+        self.try_fold_bands(colors.by_sample(),
+            Vec::<u8>::new,
+            |facets, triangle, surface| {
+                let attributes = colors.color(self, &triangle, surface).map_or(0, viscam_color);
+                // Writing to a Vec can't fail
+                let _ = write_facet(facets, &triangle, attributes);
+            },
+            |facets| writer.write_facets(&facets))
+    }
+
+    /// Passes each triangle of the solid, and the surface it belongs to, to `emit`.
+    ///
+    /// Triangles are generated a row at a time, and never stored.
+    /// Neighbouring top faces of the same height are combined,
+    /// and with `by_color`, only if their samples have the same color too,
+    /// for formats that color faces by their sample.
+    pub fn for_each_triangle<F: FnMut(Triangle, Surface)>(&self, by_color: bool, mut emit: F) {
+        let Ok(()) = self.try_for_each_triangle::<Infallible, _>(by_color, |triangle, surface| {
+            emit(triangle, surface);
+            Ok(())
+        });
+    }
+
+    /// Like `for_each_triangle`, but stops at the first error returned by `emit`.
+    pub fn try_for_each_triangle<E, F>(&self, by_color: bool, mut emit: F) -> Result<(), E>
+    where F: FnMut(Triangle, Surface) -> Result<(), E> {
+        for j in 0..self.size[1] + 1 {
+            self.try_for_each_triangle_in_row(j, by_color, &mut emit)?;
+        }
+        Ok(())
+    }
+
+    /// Meshes the heightmap a band of rows at a time, folding the triangles of
+    /// each band into a value with `fold` (starting from `init()`).
+    /// The values are passed to `emit` on this thread, in order.
+    /// Top faces are combined as in `for_each_triangle`.
+    ///
+    /// With the `parallel` feature, bands are folded on all cores,
+    /// so work done in `fold` (rather than `emit`) is spread across them.
+    /// The result is the same as from `try_fold_bands_serial`.
+    pub fn try_fold_bands<T, E, I, G, F>(&self, by_color: bool, init: I, fold: G, emit: F) -> Result<(), E>
+    where
+        T: Send,
+        I: Fn() -> T + Sync,
+        G: Fn(&mut T, Triangle, Surface) + Sync,
+        F: FnMut(T) -> Result<(), E>
+    {
+        #[cfg(feature = "parallel")]
+        return self.try_fold_bands_parallel(by_color, init, fold, emit);
+        #[cfg(not(feature = "parallel"))]
+        return self.try_fold_bands_serial(by_color, init, fold, emit);
+    }
+
+    /// Like `try_fold_bands`, but never stops early
+    pub fn fold_bands<T, I, G, F>(&self, by_color: bool, init: I, fold: G, mut emit: F)
+    where
+        T: Send,
+        I: Fn() -> T + Sync,
+        G: Fn(&mut T, Triangle, Surface) + Sync,
+        F: FnMut(T)
+    {
+        let Ok(()) = self.try_fold_bands::<T, Infallible, _, _, _>(by_color, init, fold, |value| {
+            emit(value);
+            Ok(())
+        });
+    }
+
+    /// Like `try_fold_bands`, but always on this thread
+    pub fn try_fold_bands_serial<T, E, I, G, F>(&self, by_color: bool, init: I, fold: G, mut emit: F)
+    -> Result<(), E>
+    where
+        I: Fn() -> T,
+        G: Fn(&mut T, Triangle, Surface),
+        F: FnMut(T) -> Result<(), E>
+    {
+        let rows: Vec<i32> = (0..self.size[1] + 1).collect();
+        for band in rows.chunks(BAND_ROWS) {
+            emit(self.fold_rows(band, by_color, &init, &fold))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    fn try_fold_bands_parallel<T, E, I, G, F>(&self, by_color: bool, init: I, fold: G, mut emit: F)
+    -> Result<(), E>
+    where
+        T: Send,
+        I: Fn() -> T + Sync,
+        G: Fn(&mut T, Triangle, Surface) + Sync,
+        F: FnMut(T) -> Result<(), E>
+    {
+        if rayon::current_num_threads() == 1 {
+            return self.try_fold_bands_serial(by_color, init, fold, emit);
+        }
+        let rows: Vec<i32> = (0..self.size[1] + 1).collect();
+        // A few bands per thread at a time, so that memory use stays bounded
+        let group_rows = BAND_ROWS * rayon::current_num_threads() * 4;
+        for group in rows.chunks(group_rows) {
+            let values: Vec<T> = group.par_chunks(BAND_ROWS)
+                .map(|band| self.fold_rows(band, by_color, &init, &fold))
+                .collect();
+            for value in values {
+                emit(value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fold_rows<T, I, G>(&self, rows: &[i32], by_color: bool, init: &I, fold: &G) -> T
+    where
+        I: Fn() -> T,
+        G: Fn(&mut T, Triangle, Surface)
+    {
+        let mut value = init();
+        for &j in rows {
+            let Ok(()) = self.try_for_each_triangle_in_row::<Infallible, _>(j, by_color, |triangle, surface| {
+                fold(&mut value, triangle, surface);
+                Ok(())
+            });
+        }
+        value
+    }
+
+    /// Triangles of row `j` (from 0 to `size.y` inclusive):
+    /// the tops and bases of its samples, and the walls on their lower and left edges
+    fn try_for_each_triangle_in_row<E, F>(&self, j: i32, by_color: bool, mut emit: F) -> Result<(), E>
+    where F: FnMut(Triangle, Surface) -> Result<(), E> {
         let scale = self.scale;
         let x_scale = Vec2::new(scale[0], 0.);
         let y_scale = Vec2::new(0., scale[1]);
-        for j in 0 ..self.size[1] + 1 {
-            // when does I next change
-            // (used to combine all surfaces in a row into a single rect)
-            let mut next_i = 0;
-            for i in 0..self.size[0] + 1 {
-                let z = self.sample(i, j);
-                let corner = Vec2::new(i as f32, j as f32).component_mul(&self.scale);
-                if i < self.size[0] && j < self.size[1] && z > 0. && i >= next_i {
-                    for ni in (i + 1)..=self.size[0] {
-                        next_i = ni;
-                        if self.sample(ni, j) != z {
-                            break;
-                        }
+        // when does I next change
+        // (used to combine all surfaces in a row into a single rect)
+        let mut next_i = 0;
+        for i in 0..self.size[0] + 1 {
+            let z = self.sample(i, j);
+            let color = self.color(i, j);
+            let corner = Vec2::new(i as f32, j as f32).component_mul(&self.scale);
+            if i < self.size[0] && j < self.size[1] && z > 0. && i >= next_i {
+                for ni in (i + 1)..=self.size[0] {
+                    next_i = ni;
+                    if self.sample(ni, j) != z || by_color && self.color(ni, j) != color {
+                        break;
                     }
-                    let i_count = (next_i - i) as f32;
-                    let xs = x_scale * i_count;
-                    add_rect(&mut result,
-                        [
-                            corner.insert_row(2, z),
-                            (corner + xs).insert_row(2, z),
-                            (corner + xs + y_scale).insert_row(2, z),
-                            (corner + y_scale).insert_row(2, z)
-                        ]);
-                    add_rect(&mut result,
-                        [
-                            (corner + xs).insert_row(2, 0.),
-                            (corner).insert_row(2, 0.),
-                            (corner + y_scale).insert_row(2, 0.),
-                            (corner + xs + y_scale).insert_row(2, 0.)
-                        ]);
                 }
-                let bottom_z = self.sample(i, j - 1);
+                let i_count = (next_i - i) as f32;
+                let xs = x_scale * i_count;
+                add_rect(&mut emit,
+                    [
+                        corner.insert_row(2, z),
+                        (corner + xs).insert_row(2, z),
+                        (corner + xs + y_scale).insert_row(2, z),
+                        (corner + y_scale).insert_row(2, z)
+                    ],
+                    Surface::Top(i, j))?;
+                add_rect(&mut emit,
+                    [
+                        (corner + xs).insert_row(2, 0.),
+                        (corner).insert_row(2, 0.),
+                        (corner + y_scale).insert_row(2, 0.),
+                        (corner + xs + y_scale).insert_row(2, 0.)
+                    ],
+                    Surface::Base)?;
+            }
+            // Walls between samples of the same height have no area,
+            // so their triangles would be degenerate
+            let bottom_z = self.sample(i, j - 1);
+            if bottom_z != z {
                 let bottom_corners = [
                     (corner).insert_row(2, bottom_z),
                     (corner + x_scale).insert_row(2, bottom_z),
                     (corner + x_scale).insert_row(2, z),
                     (corner).insert_row(2, z)
                 ];
-                add_rect(&mut result, bottom_corners);
-                let left_z = self.sample(i - 1, j);
+                add_rect(&mut emit, bottom_corners, Surface::Wall)?;
+            }
+            let left_z = self.sample(i - 1, j);
+            if left_z != z {
                 let left_corners = [
                     (corner + y_scale).insert_row(2, left_z),
                     (corner).insert_row(2, left_z),
                     (corner).insert_row(2, z),
                     (corner + y_scale).insert_row(2, z)
                 ];
-                add_rect(&mut result, left_corners);
+                add_rect(&mut emit, left_corners, Surface::Wall)?;
             }
         }
-        // let mut extents = self.scale;
-        // extents[0] *= self.size[0] as f32;
-        // extents[1] *= self.size[1] as f32;
-        // let floor_corners = [
-        //     Vec3::new(0.,0.,0.),
-        //     Vec3::new(0.,extents[1],0.),
-        //     Vec3::new(extents[0],extents[1],0.),
-        //     Vec3::new(extents[0],0.,0.),
-        // ];
-        // add_rect(&mut result, floor_corners);
-        result
+        Ok(())
     }
 }
 
-pub fn read_heightmap_image(filename: &str)
--> Result<Heightmap, ReadError> {
-    let image = ImageReader::open(filename)?.decode()?;
+/// Which part of each pixel sets the height of an image heightmap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Channel {
+    /// The brightest of red, green and blue
+    Max,
+    Red,
+    Green,
+    Blue,
+    /// Perceived brightness
+    Luma,
+    Alpha
+}
+
+/// Default height of a full intensity pixel, as a fraction of the image's
+/// larger dimension (with the default scale of one unit per pixel)
+pub const DEFAULT_RELIEF_RATIO: f32 = 1. / 32.;
+
+/// Settings for turning an image into a heightmap
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ImageOptions {
+    pub channel: Channel,
+    /// Height of a full intensity pixel.
+    /// If None, it is `DEFAULT_RELIEF_RATIO` of the larger image dimension.
+    pub max_height: Option<f32>,
+    /// Add up to 1/255 of random height to pixels with no red
+    /// (and some intensity), so flat areas of them get a rough texture
+    pub noise: bool,
+    /// Leave pixels that are more than half transparent out of the mesh
+    pub alpha_mask: bool
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self { channel: Channel::Max, max_height: None, noise: true, alpha_mask: false }
+    }
+}
+
+/// Converts a decoded image into a heightmap, keeping the pixel colors
+#[cfg(feature = "image-formats")]
+pub fn heightmap_from_image(image: &DynamicImage, options: &ImageOptions) -> Heightmap {
     let size = TVec2::<i32>::new(image.width() as i32, image.height() as i32);
     let scale = Vec2::new(1., 1.);
+    let max_height = options.max_height.unwrap_or(size.max() as f32 * DEFAULT_RELIEF_RATIO);
     let mut samples = Vec::<f32>::new();
+    let mut colors = Vec::<[u8; 3]>::new();
+    let mut coverage = Vec::<f32>::new();
     for y in 0..image.height() {
         for x in 0..image.width() {
             let pixel = image.get_pixel(x, y);
-            let max_channel = pixel[0].max(pixel[1]).max(pixel[2]) as f32 / 255.;
+            let intensity = match options.channel {
+                Channel::Max => pixel[0].max(pixel[1]).max(pixel[2]),
+                Channel::Red => pixel[0],
+                Channel::Green => pixel[1],
+                Channel::Blue => pixel[2],
+                Channel::Luma => pixel.to_luma()[0],
+                Channel::Alpha => pixel[3]
+            } as f32 / 255.;
             let mut randomness = 0.;
-            if pixel[0] == 0 && max_channel > 0. {
+            if options.noise && pixel[0] == 0 && intensity > 0. {
                 randomness = 1./255.;
             }
-            let sample = max_channel + randomness * random::<f32>();
-            samples.push(sample * size.max() as f32 * 1./32.);
+            let sample = intensity + randomness * random::<f32>();
+            samples.push(if options.alpha_mask && pixel[3] < 128 { f32::NAN } else { sample * max_height });
+            colors.push([pixel[0], pixel[1], pixel[2]]);
+            if options.alpha_mask {
+                coverage.push(pixel[3] as f32 / 255.);
+            }
         }
     }
-    Ok(Heightmap{size, scale, samples, invert_y: true})
+    let coverage = if options.alpha_mask { Some(coverage) } else { None };
+    Heightmap{size, scale, samples, colors: Some(colors), coverage, invert_y: true, unit: Unit::Millimeter}
 }
 
-pub fn read_heightmap(file: File)
+#[cfg(feature = "image-formats")]
+pub fn read_heightmap_image(filename: &str, options: &ImageOptions)
 -> Result<Heightmap, ReadError> {
-    let reader = BufReader::new(file);
+    let image = ImageReader::open(filename)?.decode()?;
+    Ok(heightmap_from_image(&image, options))
+}
+
+/// Reads an image heightmap from memory (any format the image crate can decode)
+#[cfg(feature = "image-formats")]
+pub fn read_heightmap_image_bytes(bytes: &[u8], options: &ImageOptions)
+-> Result<Heightmap, ReadError> {
+    let image = image::load_from_memory(bytes)?;
+    Ok(heightmap_from_image(&image, options))
+}
+
+/// True if the start of a file is that of an image
+#[cfg(feature = "image-formats")]
+fn is_image(magic: &[u8]) -> bool {
+    image::guess_format(magic).is_ok()
+}
+
+#[cfg(not(feature = "image-formats"))]
+pub(crate) fn no_image_support() -> ReadError {
+    ReadError::Image("images can't be read without the image-formats feature".to_string())
+}
+
+#[cfg(not(feature = "image-formats"))]
+pub fn read_heightmap_image(_filename: &str, _options: &ImageOptions)
+-> Result<Heightmap, ReadError> {
+    Err(no_image_support())
+}
+
+#[cfg(not(feature = "image-formats"))]
+pub fn read_heightmap_image_bytes(_bytes: &[u8], _options: &ImageOptions)
+-> Result<Heightmap, ReadError> {
+    Err(no_image_support())
+}
+
+/// Without image support, anything that can't be the start of a text heightmap
+/// is taken to be an image, so that reading it gives a clear error
+#[cfg(not(feature = "image-formats"))]
+fn is_image(magic: &[u8]) -> bool {
+    magic.first().is_some_and(|c| !(c.is_ascii_digit() || c.is_ascii_whitespace() || *c == b'-'))
+}
+
+/// Loads an image or text heightmap, detecting the format from the file contents
+pub fn load_heightmap(filename: &str, options: &ImageOptions)
+-> Result<Heightmap, ReadError> {
+    let mut file = File::open(filename)?;
+    let mut magic = Vec::<u8>::new();
+    (&mut file).take(16).read_to_end(&mut magic)?;
+    file.rewind()?;
+    if is_image(&magic) {
+        read_heightmap_image(filename, options)
+    } else {
+        read_heightmap(file)
+    }
+}
+
+/// Reads an image or text heightmap from memory, detecting the format from its contents
+pub fn load_heightmap_bytes(bytes: &[u8], options: &ImageOptions)
+-> Result<Heightmap, ReadError> {
+    if is_image(bytes) {
+        read_heightmap_image_bytes(bytes, options)
+    } else {
+        read_heightmap(bytes)
+    }
+}
+
+pub fn read_heightmap<R: Read>(input: R)
+-> Result<Heightmap, ReadError> {
+    let reader = BufReader::new(input);
     let mut size = TVec2::<i32>::new(0, 0);
     let mut scale = Vec2::new(1., 1.);
     let mut samples = Vec::<f32>::new();
@@ -134,19 +571,85 @@ pub fn read_heightmap(file: File)
         let line = line_result?;
         match line_num {
             0 => {
-                let parts: Vec<&str> = line.split(',').collect();
-                size[0] = parts[0].trim().parse()?;
-                size[1] = parts[1].trim().parse()?;
+                let (x, y) = line.split_once(',').ok_or(ReadError::Format)?;
+                size[0] = x.trim().parse()?;
+                size[1] = y.trim().parse()?;
             },
             1 => {
-                let parts: Vec<&str> = line.split(',').collect();
-                scale[0] = parts[0].trim().parse()?;
-                scale[1] = parts[1].trim().parse()?;
+                let (x, y) = line.split_once(',').ok_or(ReadError::Format)?;
+                scale[0] = x.trim().parse()?;
+                scale[1] = y.trim().parse()?;
             },
             _ => {
-                samples.push(line.trim().parse()?);
+                if !line.trim().is_empty() {
+                    samples.push(line.trim().parse()?);
+                }
             }
         }
     }
-    Ok(Heightmap{size, scale, samples, invert_y: false})
-}
\ No newline at end of file
+    if size.x < 0 || size.y < 0 {
+        return Err(ReadError::Format);
+    }
+    // Sizes too big to multiply can't match any number of samples
+    let sample_count = (size.x as usize).checked_mul(size.y as usize).ok_or(ReadError::Format)?;
+    if samples.len() != sample_count {
+        return Err(ReadError::Format);
+    }
+    Ok(Heightmap{size, scale, samples, colors: None, coverage: None, invert_y: false, unit: Unit::Millimeter})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heightmap(size: (i32, i32), samples: Vec<f32>) -> Heightmap {
+        Heightmap {
+            size: TVec2::new(size.0, size.1),
+            scale: Vec2::new(1., 1.),
+            samples,
+            colors: None,
+            coverage: None,
+            invert_y: false,
+            unit: Unit::Millimeter
+        }
+    }
+
+    fn has_area(triangle: &Triangle) -> bool {
+        (triangle[1] - triangle[0]).cross(&(triangle[2] - triangle[0])) != Vec3::zeros()
+    }
+
+    #[test]
+    fn reads_text_heightmaps() {
+        let heightmap = read_heightmap("2, 1\n0.5, 0.5\n1\n2\n".as_bytes()).unwrap();
+        assert_eq!(heightmap.size, TVec2::new(2, 1));
+        assert_eq!(heightmap.samples, vec![1., 2.]);
+        assert!(matches!(read_heightmap("2, 2\n1, 1\n1\n2\n3\n".as_bytes()), Err(ReadError::Format)));
+    }
+
+    #[test]
+    fn sizes_that_overflow_are_a_format_error() {
+        // 65536 * 65536 wraps around to 0 in 32 bits
+        assert!(matches!(read_heightmap("65536, 65536\n1, 1\n".as_bytes()), Err(ReadError::Format)));
+        assert!(matches!(read_heightmap("2147483647, 2147483647\n1, 1\n1\n".as_bytes()),
+            Err(ReadError::Format)));
+    }
+
+    #[test]
+    fn walls_between_samples_of_the_same_height_are_skipped() {
+        // Each row's top and base (8), and two triangles for each side of each sample
+        // along the edges (20), but none for the 7 sides between samples,
+        // which used to take 14 more
+        let triangles = heightmap((3, 2), vec![1.; 6]).get_triangles();
+        assert_eq!(triangles.len(), 28);
+        assert!(triangles.iter().all(has_area));
+    }
+
+    #[test]
+    fn triangles_have_area() {
+        let samples = vec![1., 1., 2., 0., 2., 2., 1., f32::NAN, 3., 3., 3., 1.];
+        let triangles = heightmap((4, 3), samples).get_triangles();
+        // 6 runs of tops and bases, and 23 walls between different heights
+        assert_eq!(triangles.len(), 70);
+        assert!(triangles.iter().all(has_area));
+    }
+}
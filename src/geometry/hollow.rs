@@ -0,0 +1,332 @@
+use std::io::Write;
+extern crate nalgebra_glm as glm;
+use glm::Vec2;
+use serde::{Deserialize, Serialize};
+use crate::geometry::triangle::*;
+use crate::geometry::heightmap::*;
+
+/// Hollowing of a heightmap's solid, leaving a closed cavity inside it
+/// (or one open to the outside through drain holes in the floor)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Hollow {
+    /// Smallest thickness of the floor, roof and sides around the cavity
+    pub wall: f32,
+    /// Diameter of the drain holes
+    pub drain_diameter: f32,
+    /// Centers of drain holes through the floor, in model coordinates.
+    /// Holes only go through where there is a cavity above the floor.
+    pub drain_holes: Vec<[f32; 2]>
+}
+
+impl Default for Hollow {
+    fn default() -> Self {
+        Self { wall: 2., drain_diameter: 3., drain_holes: Vec::new() }
+    }
+}
+
+/// The solid parts of a column of the hollowed solid, from the bottom up:
+/// the floor below the cavity (unless drained), and the rest of the column
+#[derive(Clone, Copy, PartialEq)]
+struct Column {
+    intervals: [(f32, f32); 2],
+    count: usize
+}
+
+impl Column {
+    const EMPTY: Self = Self { intervals: [(0., 0.); 2], count: 0 };
+
+    fn intervals(&self) -> &[(f32, f32)] {
+        &self.intervals[..self.count]
+    }
+
+    /// Parts of this column that are empty in `other`
+    fn subtract(&self, other: &Column, result: &mut Vec<(f32, f32)>) {
+        result.clear();
+        for &(bottom, top) in self.intervals() {
+            let mut start = bottom;
+            for &(other_bottom, other_top) in other.intervals() {
+                if other_top <= start || other_bottom >= top {
+                    continue;
+                }
+                if other_bottom > start {
+                    result.push((start, other_bottom));
+                }
+                start = start.max(other_top);
+            }
+            if start < top {
+                result.push((start, top));
+            }
+        }
+    }
+}
+
+/// Columns of a hollowed heightmap
+struct Columns<'a> {
+    heightmap: &'a Heightmap,
+    wall: f32,
+    /// Height of the top of the cavity over each sample (in `Heightmap::index` order
+    /// of a map without `invert_y`), at or below `wall` where there is none
+    ceilings: Vec<f32>,
+    drained: Vec<bool>
+}
+
+/// Smallest value within `radius` places of each one, along the rows
+/// (or columns) of a `width` by `height` grid, counting values outside it as 0
+fn min_filter(values: &[f32], width: usize, height: usize, radius: usize, along_rows: bool) -> Vec<f32> {
+    let (lines, length) = if along_rows { (height, width) } else { (width, height) };
+    let index = |line: usize, k: usize| if along_rows { line * width + k } else { k * width + line };
+    let mut result = vec![0.; values.len()];
+    for line in 0..lines {
+        for k in 0..length {
+            let value = if k < radius || k + radius >= length {
+                0.
+            } else {
+                (k - radius..=k + radius).map(|n| values[index(line, n)]).fold(f32::INFINITY, f32::min)
+            };
+            result[index(line, k)] = value;
+        }
+    }
+    result
+}
+
+impl<'a> Columns<'a> {
+    fn new(heightmap: &'a Heightmap, hollow: &Hollow) -> Self {
+        let (width, height) = (heightmap.size.x as usize, heightmap.size.y as usize);
+        let mut samples = Vec::with_capacity(width * height);
+        for j in 0..heightmap.size.y {
+            for i in 0..heightmap.size.x {
+                samples.push(heightmap.sample(i, j).max(0.));
+            }
+        }
+        // The lowest surface within `wall` of each sample (along x and y, so at least
+        // `wall` away in any direction), less `wall`, keeps the roof and sides thick enough
+        let radius_x = (hollow.wall / heightmap.scale.x).ceil() as usize;
+        let radius_y = (hollow.wall / heightmap.scale.y).ceil() as usize;
+        let lowest = min_filter(&samples, width, height, radius_x, true);
+        let lowest = min_filter(&lowest, width, height, radius_y, false);
+        let ceilings = lowest.iter().map(|lowest| lowest - hollow.wall).collect();
+        let radius = hollow.drain_diameter / 2.;
+        let mut drained = Vec::with_capacity(width * height);
+        for j in 0..heightmap.size.y {
+            for i in 0..heightmap.size.x {
+                let center = Vec2::new(i as f32 + 0.5, j as f32 + 0.5).component_mul(&heightmap.scale);
+                drained.push(hollow.drain_holes.iter()
+                    .any(|[x, y]| (center - Vec2::new(*x, *y)).norm() <= radius));
+            }
+        }
+        Self { heightmap, wall: hollow.wall, ceilings, drained }
+    }
+
+    fn column(&self, i: i32, j: i32) -> Column {
+        let size = self.heightmap.size;
+        if i < 0 || j < 0 || i >= size.x || j >= size.y {
+            return Column::EMPTY;
+        }
+        let z = self.heightmap.sample(i, j);
+        if z <= 0. {
+            return Column::EMPTY;
+        }
+        let index = (j * size.x + i) as usize;
+        let ceiling = self.ceilings[index];
+        if ceiling <= self.wall {
+            Column { intervals: [(0., z), (0., 0.)], count: 1 }
+        } else if self.drained[index] {
+            Column { intervals: [(ceiling, z), (0., 0.)], count: 1 }
+        } else {
+            Column { intervals: [(0., self.wall), (ceiling, z)], count: 2 }
+        }
+    }
+
+    /// Heights where the solid changes around the vertical line through corner (i, j)
+    fn breaks(&self, i: i32, j: i32) -> Vec<f32> {
+        let mut breaks = Vec::new();
+        for (ni, nj) in [(i - 1, j - 1), (i, j - 1), (i - 1, j), (i, j)] {
+            for &(bottom, top) in self.column(ni, nj).intervals() {
+                breaks.push(bottom);
+                breaks.push(top);
+            }
+        }
+        breaks
+    }
+}
+
+/// Emits a vertical wall from `p` to `q` (with the solid on its left, looking down),
+/// between heights `bottom` and `top`. Its vertical edges are split at `p_breaks`
+/// and `q_breaks`, so that it shares vertices with the walls beside it.
+fn add_wall<F: FnMut(Triangle, Surface)>(
+    emit: &mut F,
+    (p, q): (Vec2, Vec2),
+    (bottom, top): (f32, f32),
+    (p_breaks, q_breaks): (&[f32], &[f32])) {
+    let heights = |breaks: &[f32]| {
+        let mut heights = vec![bottom, top];
+        heights.extend(breaks.iter().filter(|z| **z > bottom && **z < top));
+        heights.sort_by(f32::total_cmp);
+        heights.dedup();
+        heights
+    };
+    let p_heights = heights(p_breaks);
+    let q_heights = heights(q_breaks);
+    let (mut k, mut n) = (0, 0);
+    // Zips the two edges together, always climbing the lower one
+    while k + 1 < p_heights.len() || n + 1 < q_heights.len() {
+        let a = p.insert_row(2, p_heights[k]);
+        let b = q.insert_row(2, q_heights[n]);
+        if n + 1 < q_heights.len() && (k + 1 == p_heights.len() || q_heights[n + 1] <= p_heights[k + 1]) {
+            n += 1;
+            emit([a, b, q.insert_row(2, q_heights[n])], Surface::Wall);
+        } else {
+            k += 1;
+            emit([a, b, p.insert_row(2, p_heights[k])], Surface::Wall);
+        }
+    }
+}
+
+impl Hollow {
+    /// Passes each triangle of the hollowed solid, and the surface it belongs to, to `emit`.
+    /// Faces inside the cavity are walls.
+    ///
+    /// Unlike `Heightmap::for_each_triangle`, faces aren't combined,
+    /// and walls are split where the faces beside them meet,
+    /// so that every edge is shared by exactly two triangles.
+    pub fn for_each_triangle<F: FnMut(Triangle, Surface)>(&self, heightmap: &Heightmap, mut emit: F) {
+        let columns = Columns::new(heightmap, self);
+        let scale = heightmap.scale;
+        let corner = |i: i32, j: i32| Vec2::new(i as f32, j as f32).component_mul(&scale);
+        let mut pieces = Vec::new();
+        for j in 0..=heightmap.size.y {
+            for i in 0..=heightmap.size.x {
+                let column = columns.column(i, j);
+                let intervals = column.intervals();
+                for (k, &(bottom, top)) in intervals.iter().enumerate() {
+                    let top_surface = if k + 1 == intervals.len() { Surface::Top(i, j) } else { Surface::Wall };
+                    let bottom_surface = if bottom == 0. { Surface::Base } else { Surface::Wall };
+                    let c = [corner(i, j), corner(i + 1, j), corner(i + 1, j + 1), corner(i, j + 1)];
+                    emit([c[0].insert_row(2, top), c[1].insert_row(2, top), c[2].insert_row(2, top)], top_surface);
+                    emit([c[0].insert_row(2, top), c[2].insert_row(2, top), c[3].insert_row(2, top)], top_surface);
+                    emit([c[1].insert_row(2, bottom), c[0].insert_row(2, bottom), c[3].insert_row(2, bottom)], bottom_surface);
+                    emit([c[1].insert_row(2, bottom), c[3].insert_row(2, bottom), c[2].insert_row(2, bottom)], bottom_surface);
+                }
+                // Walls on the left edge of this sample, then on its lower edge,
+                // each facing away from the side that is solid
+                let (lower, upper) = (columns.breaks(i, j), columns.breaks(i, j + 1));
+                let left = columns.column(i - 1, j);
+                column.subtract(&left, &mut pieces);
+                for &piece in &pieces {
+                    add_wall(&mut emit, (corner(i, j + 1), corner(i, j)), piece, (&upper, &lower));
+                }
+                left.subtract(&column, &mut pieces);
+                for &piece in &pieces {
+                    add_wall(&mut emit, (corner(i, j), corner(i, j + 1)), piece, (&lower, &upper));
+                }
+                let right_breaks = columns.breaks(i + 1, j);
+                let below = columns.column(i, j - 1);
+                column.subtract(&below, &mut pieces);
+                for &piece in &pieces {
+                    add_wall(&mut emit, (corner(i, j), corner(i + 1, j)), piece, (&lower, &right_breaks));
+                }
+                below.subtract(&column, &mut pieces);
+                for &piece in &pieces {
+                    add_wall(&mut emit, (corner(i + 1, j), corner(i, j)), piece, (&right_breaks, &lower));
+                }
+            }
+        }
+    }
+
+    pub fn get_triangles(&self, heightmap: &Heightmap) -> Vec<Triangle> {
+        let mut result = Vec::new();
+        self.for_each_triangle(heightmap, |triangle, _surface| result.push(triangle));
+        result
+    }
+
+    /// Like `Heightmap::get_colored_triangles`, but hollowed
+    pub fn get_colored_triangles(&self, heightmap: &Heightmap, wall_color: [u8; 3], base_color: [u8; 3])
+    -> Vec<ColoredTriangle> {
+        let mut result = Vec::new();
+        self.for_each_triangle(heightmap, |triangle, surface| {
+            result.push((triangle, heightmap.surface_color(surface, wall_color, base_color)));
+        });
+        result
+    }
+
+    /// Like `Heightmap::write_stl`, but hollowed
+    pub fn write_stl<W: Write>(&self, heightmap: &Heightmap, writer: &mut StlWriter<W>, colors: &FacetColors)
+    -> Result<(), std::io::Error> {
+        let mut result = Ok(());
+        self.for_each_triangle(heightmap, |triangle, surface| {
+            if result.is_ok() {
+                result = match colors.color(heightmap, &triangle, surface) {
+                    Some(color) => writer.write_colored_triangle(&triangle, color),
+                    None => writer.write_triangle(&triangle)
+                };
+            }
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::geometry::units::Unit;
+
+    /// A 12 by 10 heightmap of hills, with a hole in one corner
+    fn heightmap() -> Heightmap {
+        let size = glm::TVec2::<i32>::new(12, 10);
+        let mut samples: Vec<f32> = (0..size.x * size.y)
+            .map(|k| 5. + ((k % size.x) as f32 / 2.).sin() + ((k / size.x) as f32 / 3.).cos())
+            .collect();
+        samples[0] = 0.;
+        Heightmap {
+            size,
+            scale: Vec2::new(1., 1.),
+            samples,
+            colors: None,
+            coverage: None,
+            invert_y: false,
+            unit: Unit::Millimeter
+        }
+    }
+
+    fn volume(triangles: &[Triangle]) -> f32 {
+        triangles.iter().map(|t| t[0].dot(&t[1].cross(&t[2])) / 6.).sum()
+    }
+
+    /// Checks that every edge of the hollowed solid is shared by exactly two triangles,
+    /// which use it in opposite directions, and returns the solid's volume
+    fn assert_closed(hollow: &Hollow) -> f32 {
+        let key = |v: glm::Vec3| [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()];
+        let mut edges = HashMap::<([u32; 3], [u32; 3]), usize>::new();
+        let triangles = hollow.get_triangles(&heightmap());
+        for triangle in &triangles {
+            for k in 0..3 {
+                *edges.entry((key(triangle[k]), key(triangle[(k + 1) % 3]))).or_default() += 1;
+            }
+        }
+        for (&(a, b), &count) in &edges {
+            assert_eq!(count, 1, "edge {:?} to {:?} is used {} times", a, b, count);
+            assert!(edges.contains_key(&(b, a)), "edge {:?} to {:?} has no match", a, b);
+        }
+        volume(&triangles)
+    }
+
+    #[test]
+    fn hollow_solid_is_closed() {
+        let solid = volume(&heightmap().get_triangles());
+        let hollowed = assert_closed(&Hollow { wall: 1., ..Hollow::default() });
+        assert!(hollowed > 0. && hollowed < solid);
+    }
+
+    #[test]
+    fn drained_solid_is_closed() {
+        let closed = assert_closed(&Hollow { wall: 1., ..Hollow::default() });
+        let drained = assert_closed(&Hollow {
+            wall: 1.,
+            drain_diameter: 2.,
+            drain_holes: vec![[6., 5.], [3., 3.]]
+        });
+        assert!(drained > 0. && drained < closed);
+    }
+}
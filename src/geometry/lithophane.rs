@@ -0,0 +1,113 @@
+extern crate nalgebra_glm as glm;
+use glm::{Vec2, TVec2};
+use crate::geometry::heightmap::*;
+use crate::geometry::wrap::*;
+
+/// Shape of a lithophane
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LithophaneShape {
+    Flat,
+    /// Bent into an arc (of `LithophaneOptions::angle`), standing upright
+    Curved,
+    /// Rolled into a cylinder, standing upright, as for a lamp shade
+    Cylinder
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LithophaneOptions {
+    pub shape: LithophaneShape,
+    /// Width of the image (not counting the frame)
+    pub width: f32,
+    /// Thickness of white areas, which let the most light through
+    pub min_thickness: f32,
+    /// Thickness of black areas
+    pub max_thickness: f32,
+    /// Width of a frame of `max_thickness` around the image (0 for none).
+    /// Cylinders are only framed at the top and bottom.
+    pub frame: f32,
+    /// Thickness of a foot along the bottom edge, for the lithophane
+    /// to stand on (0 for none). The foot is a quarter as tall as it is thick.
+    pub stand: f32,
+    /// Angle covered by a curved lithophane, in degrees
+    pub angle: f32
+}
+
+impl Default for LithophaneOptions {
+    fn default() -> Self {
+        Self {
+            shape: LithophaneShape::Flat,
+            width: 100.,
+            min_thickness: 0.8,
+            max_thickness: 3.,
+            frame: 0.,
+            stand: 0.,
+            angle: 120.
+        }
+    }
+}
+
+/// Image options that give the brightness of each pixel, from 0 to 1,
+/// as expected by `lithophane_heightmap`
+pub fn lithophane_image_options() -> ImageOptions {
+    ImageOptions { channel: Channel::Luma, max_height: Some(1.), noise: false, alpha_mask: false }
+}
+
+impl LithophaneOptions {
+    /// Checks that the sizes can make a lithophane
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width <= 0. {
+            Err("the width must be more than 0".to_string())
+        } else if [self.min_thickness, self.frame, self.stand].iter().any(|size| *size < 0.) {
+            Err("thicknesses and the frame can't be negative".to_string())
+        } else if self.min_thickness > self.max_thickness {
+            Err("the minimum thickness can't be more than the maximum".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// How the lithophane's heightmap is bent, if it isn't flat
+    pub fn wrap(&self) -> Option<Wrap> {
+        match self.shape {
+            LithophaneShape::Flat => None,
+            LithophaneShape::Curved => Some(Wrap { shape: WrapShape::Arc, angle: self.angle, ..Wrap::default() }),
+            LithophaneShape::Cylinder => Some(Wrap { shape: WrapShape::Cylinder, ..Wrap::default() })
+        }
+    }
+}
+
+/// Turns an image's brightness (from 0 to 1, see `lithophane_image_options`)
+/// into the thickness of a lithophane: bright areas are thin, and dark areas thick.
+///
+/// The frame and stand are added around the image.
+/// The result is flat; see `LithophaneOptions::wrap` for how to bend it.
+pub fn lithophane_heightmap(image: &Heightmap, options: &LithophaneOptions) -> Heightmap {
+    let pixel = options.width / image.size.x.max(1) as f32;
+    let frame = (options.frame / pixel).round() as i32;
+    let frame_x = if options.shape == LithophaneShape::Cylinder { 0 } else { frame };
+    let stand_rows = (options.stand / 4. / pixel).round() as i32;
+    let size = TVec2::new(image.size.x + 2 * frame_x, image.size.y + 2 * frame + stand_rows);
+    let mut samples = Vec::with_capacity((size.x * size.y) as usize);
+    for j in 0..size.y {
+        for i in 0..size.x {
+            let (x, y) = (i - frame_x, j - frame - stand_rows);
+            samples.push(if j < stand_rows {
+                options.stand.max(options.max_thickness)
+            } else if x < 0 || y < 0 || x >= image.size.x || y >= image.size.y {
+                options.max_thickness
+            } else {
+                let brightness = image.sample(x, y).clamp(0., 1.);
+                options.max_thickness - brightness * (options.max_thickness - options.min_thickness)
+            });
+        }
+    }
+    Heightmap {
+        size,
+        scale: Vec2::new(pixel, pixel),
+        samples,
+        colors: None,
+        coverage: None,
+        invert_y: false,
+        unit: image.unit
+    }
+}
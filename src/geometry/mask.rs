@@ -0,0 +1,399 @@
+extern crate nalgebra_glm as glm;
+use glm::Vec2;
+#[cfg(feature = "image-formats")]
+use image::{GenericImageView, Pixel};
+#[cfg(feature = "image-formats")]
+use image::io::Reader as ImageReader;
+use serde::{Deserialize, Serialize};
+use crate::geometry::ReadError;
+use crate::geometry::heightmap::Heightmap;
+#[cfg(not(feature = "image-formats"))]
+use crate::geometry::heightmap::no_image_support;
+
+/// Segments that each curve of an SVG path is flattened into
+const CURVE_SEGMENTS: usize = 16;
+
+/// Which samples of a heightmap are part of the object.
+/// Samples outside the mask become holes, so the walls and base follow its outline.
+///
+/// Positions are in samples from the corner of the first sample of the file,
+/// with y counting rows (down the image, as in SVG).
+/// The `polygon` and `path` shapes are filled together with the even-odd rule,
+/// so where they overlap is left out. That shape is intersected with the `image` mask,
+/// and the result (inverted, with `invert`) with the heightmap's alpha mask, if it has one.
+/// A sample is kept if at least half of it is covered.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Mask {
+    /// Image whose dark or transparent pixels are left out, stretched over the heightmap
+    pub image: Option<String>,
+    /// Corners of a polygon around the samples to keep
+    pub polygon: Vec<[f32; 2]>,
+    /// SVG path data (the `d` attribute of a path) outlining the samples to keep.
+    /// Shapes are filled with the even-odd rule, together with `polygon`,
+    /// so a shape inside another one cuts a hole in it.
+    pub path: Option<String>,
+    /// Keep the samples outside the mask instead
+    pub invert: bool
+}
+
+/// Reads SVG path data, one number or command at a time
+struct PathData<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> PathData<'a> {
+    fn skip_separators(&mut self) {
+        while self.position < self.bytes.len()
+            && (self.bytes[self.position].is_ascii_whitespace() || self.bytes[self.position] == b',') {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_separators();
+        self.bytes.get(self.position).copied()
+    }
+
+    fn error(&self, message: &str) -> ReadError {
+        ReadError::Mask(format!("{} at character {} of the path", message, self.position + 1))
+    }
+
+    fn has_number(&mut self) -> bool {
+        self.peek().is_some_and(|c| c.is_ascii_digit() || matches!(c, b'-' | b'+' | b'.'))
+    }
+
+    fn number(&mut self) -> Result<f32, ReadError> {
+        if !self.has_number() {
+            return Err(self.error("Expected a number"));
+        }
+        let start = self.position;
+        let digits = |data: &mut Self| {
+            while data.bytes.get(data.position).is_some_and(u8::is_ascii_digit) {
+                data.position += 1;
+            }
+        };
+        if matches!(self.bytes[self.position], b'-' | b'+') {
+            self.position += 1;
+        }
+        digits(self);
+        if self.bytes.get(self.position) == Some(&b'.') {
+            self.position += 1;
+            digits(self);
+        }
+        if matches!(self.bytes.get(self.position), Some(b'e' | b'E')) {
+            self.position += 1;
+            if matches!(self.bytes.get(self.position), Some(b'-' | b'+')) {
+                self.position += 1;
+            }
+            digits(self);
+        }
+        std::str::from_utf8(&self.bytes[start..self.position]).ok()
+            .and_then(|text| text.parse().ok())
+            .ok_or_else(|| self.error("Invalid number"))
+    }
+
+    fn point(&mut self) -> Result<Vec2, ReadError> {
+        Ok(Vec2::new(self.number()?, self.number()?))
+    }
+
+    /// An arc flag, which may be written without a separator after it
+    fn flag(&mut self) -> Result<bool, ReadError> {
+        let flag = match self.peek() {
+            Some(b'0') => false,
+            Some(b'1') => true,
+            _ => return Err(self.error("Expected an arc flag"))
+        };
+        self.position += 1;
+        Ok(flag)
+    }
+}
+
+/// Point at `t` along a cubic Bézier curve
+fn cubic(p: [Vec2; 4], t: f32) -> Vec2 {
+    let s = 1. - t;
+    p[0] * (s * s * s) + p[1] * (3. * s * s * t) + p[2] * (3. * s * t * t) + p[3] * (t * t * t)
+}
+
+/// Points along an SVG elliptical arc from `from` to `to`, after `from`
+/// (see "Elliptical arc implementation notes" in the SVG specification)
+fn arc_points(from: Vec2, radii: Vec2, rotation: f32, large_arc: bool, sweep: bool, to: Vec2, points: &mut Vec<Vec2>) {
+    let (mut rx, mut ry) = (radii.x.abs(), radii.y.abs());
+    if from == to {
+        return;
+    }
+    if rx == 0. || ry == 0. {
+        points.push(to);
+        return;
+    }
+    let (sin, cos) = rotation.to_radians().sin_cos();
+    let half = (from - to) / 2.;
+    let x1 = cos * half.x + sin * half.y;
+    let y1 = -sin * half.x + cos * half.y;
+    // Radii too small to reach are scaled up until they just do
+    let lambda = x1 * x1 / (rx * rx) + y1 * y1 / (ry * ry);
+    if lambda > 1. {
+        rx *= lambda.sqrt();
+        ry *= lambda.sqrt();
+    }
+    let numerator = rx * rx * ry * ry - rx * rx * y1 * y1 - ry * ry * x1 * x1;
+    let denominator = rx * rx * y1 * y1 + ry * ry * x1 * x1;
+    let sign = if large_arc == sweep { -1. } else { 1. };
+    let coefficient = sign * (numerator / denominator).max(0.).sqrt();
+    let (cx, cy) = (coefficient * rx * y1 / ry, -coefficient * ry * x1 / rx);
+    let center = Vec2::new(cos * cx - sin * cy, sin * cx + cos * cy) + (from + to) / 2.;
+    let angle = |u: Vec2, v: Vec2| (u.x * v.y - u.y * v.x).atan2(u.dot(&v));
+    let start = Vec2::new((x1 - cx) / rx, (y1 - cy) / ry);
+    let end = Vec2::new((-x1 - cx) / rx, (-y1 - cy) / ry);
+    let theta = angle(Vec2::new(1., 0.), start);
+    let mut delta = angle(start, end);
+    if !sweep && delta > 0. {
+        delta -= std::f32::consts::TAU;
+    } else if sweep && delta < 0. {
+        delta += std::f32::consts::TAU;
+    }
+    for k in 1..CURVE_SEGMENTS {
+        let t = theta + delta * k as f32 / CURVE_SEGMENTS as f32;
+        let (x, y) = (rx * t.cos(), ry * t.sin());
+        points.push(Vec2::new(cos * x - sin * y, sin * x + cos * y) + center);
+    }
+    points.push(to);
+}
+
+/// Reads SVG path data into closed polygons, flattening curves and arcs
+pub fn parse_svg_path(data: &str) -> Result<Vec<Vec<Vec2>>, ReadError> {
+    let mut data = PathData { bytes: data.as_bytes(), position: 0 };
+    let mut polygons = Vec::new();
+    let mut polygon: Vec<Vec2> = Vec::new();
+    let mut current = Vec2::zeros();
+    // Last control point of the previous command, if it was a curve,
+    // and whether the curve was cubic; S and T reflect it
+    let mut control: Option<(Vec2, bool)> = None;
+    let mut command = None;
+    while let Some(c) = data.peek() {
+        if c.is_ascii_alphabetic() {
+            data.position += 1;
+            command = Some(c);
+        } else if matches!(command, None | Some(b'Z' | b'z')) {
+            return Err(data.error("Expected a command"));
+        }
+        let c = command.unwrap_or(c);
+        let relative = c.is_ascii_lowercase();
+        let origin = if relative { current } else { Vec2::zeros() };
+        let curve = |polygon: &mut Vec<Vec2>, p: [Vec2; 4]| {
+            for k in 1..=CURVE_SEGMENTS {
+                polygon.push(cubic(p, k as f32 / CURVE_SEGMENTS as f32));
+            }
+        };
+        let previous = control.take();
+        match c.to_ascii_uppercase() {
+            b'M' => {
+                if polygon.len() > 2 {
+                    polygons.push(std::mem::take(&mut polygon));
+                }
+                current = origin + data.point()?;
+                polygon = vec![current];
+                // Further points are lines
+                command = Some(if relative { b'l' } else { b'L' });
+            },
+            b'L' => {
+                current = origin + data.point()?;
+                polygon.push(current);
+            },
+            b'H' => {
+                current.x = origin.x + data.number()?;
+                polygon.push(current);
+            },
+            b'V' => {
+                current.y = origin.y + data.number()?;
+                polygon.push(current);
+            },
+            b'C' | b'S' => {
+                let first = match (c.to_ascii_uppercase(), previous) {
+                    (b'C', _) => origin + data.point()?,
+                    (_, Some((point, true))) => current * 2. - point,
+                    _ => current
+                };
+                let second = origin + data.point()?;
+                let end = origin + data.point()?;
+                curve(&mut polygon, [current, first, second, end]);
+                control = Some((second, true));
+                current = end;
+            },
+            b'Q' | b'T' => {
+                let middle = match (c.to_ascii_uppercase(), previous) {
+                    (b'Q', _) => origin + data.point()?,
+                    (_, Some((point, false))) => current * 2. - point,
+                    _ => current
+                };
+                let end = origin + data.point()?;
+                // The same curve as a cubic one, with its control points 2/3 of the way to `middle`
+                curve(&mut polygon, [current, current + (middle - current) * (2. / 3.),
+                    end + (middle - end) * (2. / 3.), end]);
+                control = Some((middle, false));
+                current = end;
+            },
+            b'A' => {
+                let radii = data.point()?;
+                let rotation = data.number()?;
+                let large_arc = data.flag()?;
+                let sweep = data.flag()?;
+                let end = origin + data.point()?;
+                arc_points(current, radii, rotation, large_arc, sweep, end, &mut polygon);
+                current = end;
+            },
+            b'Z' => {
+                if polygon.len() > 2 {
+                    polygons.push(polygon.clone());
+                }
+                // Drawing on after closing a shape starts from its start
+                current = polygon.first().copied().unwrap_or(current);
+                polygon = vec![current];
+            },
+            _ => return Err(data.error(&format!("Unsupported command {}", c as char)))
+        }
+    }
+    if polygon.len() > 2 {
+        polygons.push(polygon);
+    }
+    Ok(polygons)
+}
+
+/// Where the horizontal line at `y` crosses the edges of the polygons, sorted along x
+fn crossings(polygons: &[Vec<Vec2>], y: f32, result: &mut Vec<f32>) {
+    result.clear();
+    for polygon in polygons {
+        for (k, a) in polygon.iter().enumerate() {
+            let b = &polygon[(k + 1) % polygon.len()];
+            if (a.y > y) != (b.y > y) {
+                result.push(a.x + (y - a.y) / (b.y - a.y) * (b.x - a.x));
+            }
+        }
+    }
+    result.sort_by(f32::total_cmp);
+}
+
+/// Distance from `p` to the nearest edge of the polygons
+fn edge_distance(polygons: &[Vec<Vec2>], p: Vec2) -> f32 {
+    let mut distance = f32::INFINITY;
+    for polygon in polygons {
+        for (k, a) in polygon.iter().enumerate() {
+            let b = &polygon[(k + 1) % polygon.len()];
+            let edge = b - a;
+            let t = (glm::dot(&(p - a), &edge) / edge.norm_squared()).clamp(0., 1.);
+            distance = distance.min((p - (a + edge * t)).norm());
+        }
+    }
+    distance
+}
+
+/// How much of each sample of a `width` by `height` grid the polygons cover, in rows.
+/// Samples beside the outline are covered in proportion to how far their center is inside it,
+/// so that it crosses between them where it crosses the polygons.
+fn polygon_coverage(polygons: &[Vec<Vec2>], width: usize, height: usize) -> Vec<f32> {
+    let mut inside = Vec::with_capacity(width * height);
+    let mut xs = Vec::new();
+    for row in 0..height {
+        crossings(polygons, row as f32 + 0.5, &mut xs);
+        // Even-odd: a sample is inside if an odd number of edges lie left of its center
+        let mut crossed = 0;
+        for column in 0..width {
+            let center = column as f32 + 0.5;
+            while crossed < xs.len() && xs[crossed] < center {
+                crossed += 1;
+            }
+            inside.push(crossed % 2 == 1);
+        }
+    }
+    let mut coverage = Vec::with_capacity(width * height);
+    for row in 0..height {
+        for column in 0..width {
+            let index = row * width + column;
+            let beside_outline = (column > 0 && inside[index - 1] != inside[index])
+                || (column + 1 < width && inside[index + 1] != inside[index])
+                || (row > 0 && inside[index - width] != inside[index])
+                || (row + 1 < height && inside[index + width] != inside[index]);
+            coverage.push(if !beside_outline {
+                if inside[index] { 1. } else { 0. }
+            } else {
+                let distance = edge_distance(polygons, Vec2::new(column as f32 + 0.5, row as f32 + 0.5));
+                let distance = if inside[index] { distance } else { -distance };
+                (0.5 + distance).clamp(0., 1.)
+            });
+        }
+    }
+    coverage
+}
+
+impl Mask {
+    /// Polygons of `polygon` and `path`
+    fn polygons(&self) -> Result<Vec<Vec<Vec2>>, ReadError> {
+        let mut polygons = match &self.path {
+            Some(path) => parse_svg_path(path)?,
+            None => Vec::new()
+        };
+        if self.polygon.len() > 2 {
+            polygons.push(self.polygon.iter().map(|[x, y]| Vec2::new(*x, *y)).collect());
+        }
+        Ok(polygons)
+    }
+
+    /// How much of each sample the mask image covers (by its brightness and opacity),
+    /// in the order of `Heightmap::samples`
+    #[cfg(feature = "image-formats")]
+    fn image_coverage(path: &str, heightmap: &Heightmap) -> Result<Vec<f32>, ReadError> {
+        let image = ImageReader::open(path)?.decode()?;
+        let (width, height) = (heightmap.size.x as u32, heightmap.size.y as u32);
+        let mut coverage = Vec::with_capacity(heightmap.samples.len());
+        for row in 0..height {
+            for column in 0..width {
+                // The pixel under the sample's center
+                let x = ((column as f32 + 0.5) * image.width() as f32 / width as f32) as u32;
+                let y = ((row as f32 + 0.5) * image.height() as f32 / height as f32) as u32;
+                let [luma, alpha] = image.get_pixel(x.min(image.width() - 1), y.min(image.height() - 1))
+                    .to_luma_alpha().0;
+                coverage.push(luma as f32 * alpha as f32 / (255. * 255.));
+            }
+        }
+        Ok(coverage)
+    }
+
+    #[cfg(not(feature = "image-formats"))]
+    fn image_coverage(_path: &str, _heightmap: &Heightmap) -> Result<Vec<f32>, ReadError> {
+        Err(no_image_support())
+    }
+
+    /// Turns the samples outside the mask into holes, and records how much of each one it covers
+    pub fn apply(&self, heightmap: &mut Heightmap) -> Result<(), ReadError> {
+        let mut coverage = match &self.image {
+            Some(path) => Self::image_coverage(path, heightmap)?,
+            None => vec![1.; heightmap.samples.len()]
+        };
+        let polygons = self.polygons()?;
+        if !polygons.is_empty() {
+            let shape = polygon_coverage(&polygons, heightmap.size.x as usize, heightmap.size.y as usize);
+            for (coverage, shape) in coverage.iter_mut().zip(shape) {
+                *coverage = coverage.min(shape);
+            }
+        }
+        if self.invert {
+            for coverage in &mut coverage {
+                *coverage = 1. - *coverage;
+            }
+        }
+        // Along with an alpha mask, if there is one
+        if let Some(existing) = &heightmap.coverage {
+            for (coverage, existing) in coverage.iter_mut().zip(existing) {
+                *coverage = coverage.min(*existing);
+            }
+        }
+        for (sample, coverage) in heightmap.samples.iter_mut().zip(&coverage) {
+            if *coverage < 0.5 {
+                *sample = f32::NAN;
+            }
+        }
+        heightmap.coverage = Some(coverage);
+        Ok(())
+    }
+}
@@ -1,14 +1,31 @@
 pub mod triangle;
 pub mod heightmap;
+pub mod threemf;
+pub mod ply;
+pub mod gltf;
+pub mod stats;
+pub mod units;
+pub mod tiles;
+pub mod wrap;
+pub mod lithophane;
+pub mod hollow;
+pub mod mask;
+pub mod outline;
+pub mod contours;
 use std::num::{ParseFloatError, ParseIntError};
+#[cfg(feature = "image-formats")]
 use image::ImageError;
 
 #[derive(Debug, Clone)]
 pub enum ReadError {
-    IO,
+    IO(std::io::ErrorKind),
     ParseFloat,
     ParseInt,
-    Image
+    Image(String),
+    /// The file doesn't follow the heightmap format
+    Format,
+    /// The mask's path can't be read
+    Mask(String)
 }
 impl From<ParseIntError> for ReadError {
     fn from(_e: ParseIntError) -> Self {Self::ParseInt}
@@ -17,19 +34,60 @@ impl From<ParseFloatError> for ReadError {
     fn from(_e: ParseFloatError) -> Self {Self::ParseFloat}
 }
 impl From<std::io::Error> for ReadError {
-    fn from(_e: std::io::Error) -> Self {Self::IO}
+    fn from(e: std::io::Error) -> Self {Self::IO(e.kind())}
 }
+#[cfg(feature = "image-formats")]
 impl From<ImageError> for ReadError {
-    fn from(_e: ImageError) -> Self {Self::Image}
+    fn from(e: ImageError) -> Self {Self::Image(e.to_string())}
 }
 impl std::fmt::Display for ReadError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         match self {
-            Self::IO => write!(f, "Read/Write Error"),
+            Self::IO(kind) => write!(f, "Read/Write Error: {}", kind),
             Self::ParseFloat => write!(f, "Parse float Error"),
             Self::ParseInt => write!(f, "Parse int Error"),
-            Self::Image => write!(f, "Image Error")
+            Self::Image(message) => write!(f, "Image Error: {}", message),
+            Self::Format => write!(f, "Invalid heightmap format"),
+            Self::Mask(message) => write!(f, "Invalid mask: {}", message)
         }
     }
 }
-impl std::error::Error for ReadError {}
\ No newline at end of file
+impl std::error::Error for ReadError {}
+
+/// Errors from reading an STL file, with enough detail to locate the problem.
+#[derive(Debug)]
+pub enum StlError {
+    IO(std::io::Error),
+    /// The file is too short to hold the triangles its header declares
+    Truncated { expected: u64, actual: u64 },
+    /// The file has bytes past the triangles its header declares
+    TrailingData { expected: u64, actual: u64 },
+    /// ASCII STL that doesn't follow the `solid`/`facet`/`vertex` grammar
+    Syntax { line: usize, message: String },
+    /// A vertex or normal coordinate is NaN or infinite
+    NonFinite { facet: usize },
+    /// A stored normal points away from the normal given by the vertex winding
+    NormalMismatch { facet: usize, degrees: f32 }
+}
+impl From<std::io::Error> for StlError {
+    fn from(e: std::io::Error) -> Self {Self::IO(e)}
+}
+impl std::fmt::Display for StlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::IO(e) => write!(f, "Read Error: {}", e),
+            Self::Truncated { expected, actual } => write!(f,
+                "Truncated STL: expected {} bytes, found {}", expected, actual),
+            Self::TrailingData { expected, actual } => write!(f,
+                "Unexpected data after STL triangles: expected {} bytes, found {}",
+                expected, actual),
+            Self::Syntax { line, message } => write!(f,
+                "ASCII STL syntax error on line {}: {}", line, message),
+            Self::NonFinite { facet } => write!(f,
+                "Facet {} has a NaN or infinite coordinate", facet),
+            Self::NormalMismatch { facet, degrees } => write!(f,
+                "Facet {} normal is {:.1} degrees from its winding", facet, degrees)
+        }
+    }
+}
+impl std::error::Error for StlError {}
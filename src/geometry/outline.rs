@@ -0,0 +1,411 @@
+use std::io::Write;
+extern crate nalgebra_glm as glm;
+use glm::{Vec2, Vec3};
+use crate::geometry::triangle::*;
+use crate::geometry::heightmap::*;
+
+/// Value of `Outline::field` along the outline
+const LEVEL: f32 = 0.5;
+
+/// The outline of a heightmap's solid samples, traced with marching squares
+/// through the cells between sample centers.
+///
+/// Positions are in samples, with sample (i, j) centered on (i + 0.5, j + 0.5).
+struct Outline<'a> {
+    heightmap: &'a Heightmap
+}
+
+/// Part of a sample inside the outline, with a flat top
+struct Piece {
+    /// Convex and counterclockwise, in samples.
+    /// Pieces that touch share every point along the edge between them,
+    /// so each edge of one is either an edge of the other, the other way round, or on the outline.
+    polygon: Vec<Vec2>,
+    height: f32,
+    /// The sample whose top it is
+    owner: (i32, i32)
+}
+
+impl Piece {
+    fn has_edge(&self, a: Vec2, b: Vec2) -> bool {
+        let n = self.polygon.len();
+        (0..n).any(|k| self.polygon[k] == a && self.polygon[(k + 1) % n] == b)
+    }
+}
+
+fn center((i, j): (i32, i32)) -> Vec2 {
+    Vec2::new(i as f32 + 0.5, j as f32 + 0.5)
+}
+
+/// Removes repeated points from a polygon (where the outline goes through a sample center),
+/// returning nothing if fewer than three are left
+fn without_repeats(mut polygon: Vec<Vec2>) -> Option<Vec<Vec2>> {
+    polygon.dedup();
+    if polygon.len() > 1 && polygon.first() == polygon.last() {
+        polygon.pop();
+    }
+    (polygon.len() >= 3).then_some(polygon)
+}
+
+/// Part of a convex polygon on one side of the line where coordinate `axis` is `value`
+fn clip(polygon: &[Vec2], axis: usize, value: f32, below: bool) -> Vec<Vec2> {
+    let keep = |p: &Vec2| if below { p[axis] <= value } else { p[axis] >= value };
+    let mut result: Vec<Vec2> = Vec::new();
+    for (k, a) in polygon.iter().enumerate() {
+        let b = &polygon[(k + 1) % polygon.len()];
+        if keep(a) {
+            result.push(*a);
+        }
+        if keep(a) != keep(b) {
+            let mut p = a + (b - a) * ((value - a[axis]) / (b[axis] - a[axis]));
+            // Exactly on the line, so that the polygon on its other side shares the point
+            p[axis] = value;
+            result.push(p);
+        }
+    }
+    result
+}
+
+impl<'a> Outline<'a> {
+    fn in_map(&self, (i, j): (i32, i32)) -> bool {
+        i >= 0 && j >= 0 && i < self.heightmap.size.x && j < self.heightmap.size.y
+    }
+
+    fn solid(&self, sample: (i32, i32)) -> bool {
+        self.heightmap.sample(sample.0, sample.1) > 0.
+    }
+
+    /// Value that the outline is traced through, at `LEVEL`:
+    /// how much of the sample a mask covers (at least `LEVEL` if it is solid, and less if not),
+    /// or 1 for solid samples and 0 for holes without one.
+    /// Outside the map, it is that of the nearest sample, so the corners of the map stay square.
+    fn field(&self, (i, j): (i32, i32)) -> f32 {
+        let size = self.heightmap.size;
+        let sample = (i.clamp(0, size.x - 1), j.clamp(0, size.y - 1));
+        let coverage = self.heightmap.coverage(sample.0, sample.1);
+        if self.solid(sample) {
+            coverage.map_or(1., |coverage| coverage.max(LEVEL))
+        } else {
+            coverage.filter(|coverage| *coverage < LEVEL).unwrap_or(0.)
+        }
+    }
+
+    /// Where the outline crosses the line between the centers of neighbouring samples,
+    /// one inside and one outside
+    fn crossing(&self, a: (i32, i32), b: (i32, i32)) -> Vec2 {
+        // The same way round from either side, so that neighbouring cells share the point
+        let (a, b) = if (a.1, a.0) < (b.1, b.0) { (a, b) } else { (b, a) };
+        let (field_a, field_b) = (self.field(a), self.field(b));
+        center(a) + (center(b) - center(a)) * ((LEVEL - field_a) / (field_b - field_a))
+    }
+
+    /// True if the sample and the cells around it are all inside the outline
+    fn whole(&self, (i, j): (i32, i32)) -> bool {
+        (-1..=1).all(|dj| (-1..=1).all(|di| self.field((i + di, j + dj)) >= LEVEL))
+    }
+
+    /// Parts of the cell between the centers of samples (i, j) and (i + 1, j + 1)
+    /// inside the outline, as convex, counterclockwise polygons
+    fn cell(&self, (i, j): (i32, i32)) -> Vec<Vec<Vec2>> {
+        let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+        let inside = corners.map(|corner| self.field(corner) >= LEVEL);
+        let mut polygon = Vec::new();
+        for k in 0..4 {
+            if inside[k] {
+                polygon.push(center(corners[k]));
+            }
+            if inside[k] != inside[(k + 1) % 4] {
+                polygon.push(self.crossing(corners[k], corners[(k + 1) % 4]));
+            }
+        }
+        if polygon.len() < 3 {
+            return Vec::new();
+        }
+        // Opposite corners inside are only joined if the middle of the cell is inside too
+        if inside == [true, false, true, false] || inside == [false, true, false, true] {
+            let middle = corners.iter().map(|corner| self.field(*corner)).sum::<f32>() / 4.;
+            if middle < LEVEL {
+                if inside[0] {
+                    polygon.rotate_right(1);
+                }
+                return [polygon[..3].to_vec(), polygon[3..].to_vec()].into_iter()
+                    .filter_map(without_repeats)
+                    .collect();
+            }
+        }
+        without_repeats(polygon).into_iter().collect()
+    }
+
+    /// Height of the part of `sample` in the cell from `cell`, and the sample whose top it is:
+    /// its own, or for holes, that of the highest solid sample around the cell
+    fn quadrant_height(&self, sample: (i32, i32), (i, j): (i32, i32)) -> (f32, (i32, i32)) {
+        if self.solid(sample) {
+            return (self.heightmap.sample(sample.0, sample.1), sample);
+        }
+        [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)].into_iter()
+            .filter(|corner| self.solid(*corner))
+            .map(|corner| (self.heightmap.sample(corner.0, corner.1), corner))
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap_or((0., sample))
+    }
+
+    /// The pieces of a sample: the whole of it away from the outline,
+    /// and along it, the part of each of its quarters inside the cell that the quarter is in
+    fn pieces(&self, (i, j): (i32, i32)) -> Vec<Piece> {
+        if !self.in_map((i, j)) {
+            return Vec::new();
+        }
+        if self.whole((i, j)) {
+            // With the middle of each side that the next sample's quarters meet
+            let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)]
+                .map(|(x, y)| Vec2::new(x as f32, y as f32));
+            let neighbours = [(i, j - 1), (i + 1, j), (i, j + 1), (i - 1, j)];
+            let mut polygon = Vec::new();
+            for k in 0..4 {
+                polygon.push(corners[k]);
+                if self.in_map(neighbours[k]) && !self.whole(neighbours[k]) {
+                    polygon.push((corners[k] + corners[(k + 1) % 4]) / 2.);
+                }
+            }
+            return vec![Piece { polygon, height: self.heightmap.sample(i, j), owner: (i, j) }];
+        }
+        let mut pieces = Vec::new();
+        for (di, dj) in [(1, 1), (-1, 1), (-1, -1), (1, -1)] {
+            let cell = (i.min(i + di), j.min(j + dj));
+            let (height, owner) = self.quadrant_height((i, j), cell);
+            // The edges of the sample within the cell
+            let edge = Vec2::new((i + (di + 1) / 2) as f32, (j + (dj + 1) / 2) as f32);
+            for piece in self.cell(cell) {
+                let quadrant = clip(&clip(&piece, 0, edge.x, di > 0), 1, edge.y, dj > 0);
+                if let Some(polygon) = without_repeats(quadrant) {
+                    pieces.push(Piece { polygon, height, owner });
+                }
+            }
+        }
+        pieces
+    }
+
+    /// Heights of the pieces that can have `p` as a corner:
+    /// those of the quarters of samples around it
+    fn heights_at(&self, p: Vec2) -> Vec<f32> {
+        // Quarters are half a sample wide, and those of sample i are 2i and 2i + 1 along x
+        let quarters = |x: f32| {
+            let quarter = (2. * x).floor() as i32;
+            if quarter as f32 == 2. * x { quarter - 1..=quarter } else { quarter..=quarter }
+        };
+        let mut heights = Vec::new();
+        for qy in quarters(p.y) {
+            for qx in quarters(p.x) {
+                let sample = (qx.div_euclid(2), qy.div_euclid(2));
+                if self.in_map(sample) {
+                    let cell = ((qx - 1).div_euclid(2), (qy - 1).div_euclid(2));
+                    heights.push(self.quadrant_height(sample, cell).0);
+                }
+            }
+        }
+        heights
+    }
+}
+
+/// Emits a vertical wall from `a` to `b` (looking down, with the solid on its left),
+/// between heights `bottom` and `top`.
+///
+/// Its ends are split at the heights in `heights_a` and `heights_b` between those,
+/// so that they meet the ends of the walls beside them edge to edge.
+fn add_wall<F: FnMut(Triangle, Surface)>(emit: &mut F, scale: Vec2, (a, b): (Vec2, Vec2),
+    (bottom, top): (f32, f32), (heights_a, heights_b): (&[f32], &[f32])) {
+    let point = |p: Vec2, z: f32| Vec3::new(p.x * scale.x, p.y * scale.y, z);
+    let column = |heights: &[f32]| {
+        let mut column: Vec<f32> = heights.iter().copied().filter(|h| *h > bottom && *h < top).collect();
+        column.extend([bottom, top]);
+        column.sort_by(f32::total_cmp);
+        column.dedup();
+        column
+    };
+    let (column_a, column_b) = (column(heights_a), column(heights_b));
+    // Zips the two ends together from the bottom, a step up one of them per triangle
+    let (mut k_a, mut k_b) = (0, 0);
+    while k_a + 1 < column_a.len() || k_b + 1 < column_b.len() {
+        if k_b + 1 < column_b.len() && (k_a + 1 == column_a.len() || column_b[k_b + 1] <= column_a[k_a + 1]) {
+            emit([point(a, column_a[k_a]), point(b, column_b[k_b]), point(b, column_b[k_b + 1])], Surface::Wall);
+            k_b += 1;
+        } else {
+            emit([point(a, column_a[k_a]), point(b, column_b[k_b]), point(a, column_a[k_a + 1])], Surface::Wall);
+            k_a += 1;
+        }
+    }
+}
+
+/// Emits the top and base of a piece, fanned out from a corner
+/// that none of the triangles are flat from, if there is one
+fn add_faces<F: FnMut(Triangle, Surface)>(emit: &mut F, scale: Vec2, piece: &Piece) {
+    let point = |p: Vec2, z: f32| Vec3::new(p.x * scale.x, p.y * scale.y, z);
+    let polygon = &piece.polygon;
+    let n = polygon.len();
+    let has_area = |p: Vec2, q: Vec2, r: Vec2| (q - p).perp(&(r - p)) != 0.;
+    let start = (0..n)
+        .find(|&s| (1..n - 1).all(|k| has_area(polygon[s], polygon[(s + k) % n], polygon[(s + k + 1) % n])))
+        .unwrap_or(0);
+    let top = Surface::Top(piece.owner.0, piece.owner.1);
+    for k in 1..n - 1 {
+        let (p, q, r) = (polygon[start], polygon[(start + k) % n], polygon[(start + k + 1) % n]);
+        emit([point(p, piece.height), point(q, piece.height), point(r, piece.height)], top);
+        emit([point(p, 0.), point(r, 0.), point(q, 0.)], Surface::Base);
+    }
+}
+
+/// Passes each triangle of the heightmap's solid, and the surface it belongs to, to `emit`,
+/// with its outline traced smoothly between samples rather than along their edges.
+///
+/// The outline goes where a mask's coverage (see `Heightmap::coverage`) crosses a half,
+/// interpolated between sample centers, or halfway between solid samples and holes.
+/// Each sample along it is cut to the part inside the outline; parts of holes inside it
+/// take the height of the highest solid sample beside them.
+///
+/// The solid is closed, with no edges ending partway along others:
+/// walls are split wherever another wall or face meets them.
+pub fn for_each_smooth_triangle<F: FnMut(Triangle, Surface)>(heightmap: &Heightmap, mut emit: F) {
+    let outline = Outline { heightmap };
+    let scale = heightmap.scale;
+    let row = |j: i32| (0..heightmap.size.x).map(|i| outline.pieces((i, j))).collect::<Vec<_>>();
+    // The pieces of the rows before, at and after the one being meshed
+    let mut rows = [row(-1), row(0), row(1)];
+    for j in 0..heightmap.size.y {
+        let pieces_of = |(i, nj): (i32, i32)| -> &[Piece] {
+            if i < 0 || i >= heightmap.size.x { &[] } else { &rows[(nj - j + 1) as usize][i as usize] }
+        };
+        for i in 0..heightmap.size.x {
+            for piece in pieces_of((i, j)) {
+                add_faces(&mut emit, scale, piece);
+                let n = piece.polygon.len();
+                for k in 0..n {
+                    let (a, b) = (piece.polygon[k], piece.polygon[(k + 1) % n]);
+                    // The height on the other side: that of the piece there (which has the edge
+                    // the other way round), or 0 along the outline and the edge of the map
+                    let other_side = [(i, j), (i + 1, j), (i - 1, j), (i, j + 1), (i, j - 1)].into_iter()
+                        .flat_map(pieces_of)
+                        .find(|other| other.has_edge(b, a))
+                        .map_or(0., |other| other.height);
+                    if other_side < piece.height {
+                        add_wall(&mut emit, scale, (a, b), (other_side, piece.height),
+                            (&outline.heights_at(a), &outline.heights_at(b)));
+                    }
+                }
+            }
+        }
+        rows.rotate_left(1);
+        rows[2] = row(j + 2);
+    }
+}
+
+pub fn get_smooth_triangles(heightmap: &Heightmap) -> Vec<Triangle> {
+    let mut result = Vec::new();
+    for_each_smooth_triangle(heightmap, |triangle, _surface| result.push(triangle));
+    result
+}
+
+/// Like `Heightmap::get_colored_triangles`, but with a smooth outline
+pub fn get_smooth_colored_triangles(heightmap: &Heightmap, wall_color: [u8; 3], base_color: [u8; 3])
+-> Vec<ColoredTriangle> {
+    let mut result = Vec::new();
+    for_each_smooth_triangle(heightmap, |triangle, surface| {
+        result.push((triangle, heightmap.surface_color(surface, wall_color, base_color)));
+    });
+    result
+}
+
+/// Like `Heightmap::write_stl`, but with a smooth outline
+pub fn write_smooth_stl<W: Write>(heightmap: &Heightmap, writer: &mut StlWriter<W>, colors: &FacetColors)
+-> Result<(), std::io::Error> {
+    let mut result = Ok(());
+    for_each_smooth_triangle(heightmap, |triangle, surface| {
+        if result.is_ok() {
+            result = match colors.color(heightmap, &triangle, surface) {
+                Some(color) => writer.write_colored_triangle(&triangle, color),
+                None => writer.write_triangle(&triangle)
+            };
+        }
+    });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::geometry::mask::Mask;
+    use crate::geometry::units::Unit;
+
+    /// A 40 by 40 heightmap with hills, and a mask's coverage faded over 4 samples
+    /// around a circle, as from an image's alpha channel
+    fn heightmap(soft_edge: bool) -> Heightmap {
+        let size = glm::TVec2::<i32>::new(40, 40);
+        let mut samples = Vec::new();
+        let mut coverage = Vec::new();
+        for j in 0..size.y {
+            for i in 0..size.x {
+                let (x, y) = (i as f32 + 0.5, j as f32 + 0.5);
+                let faded = ((15. - (x - 20.).hypot(y - 20.)) / 4. + 0.5).clamp(0., 1.);
+                let height = 2. + (x / 5.).sin() * (y / 7.).cos();
+                samples.push(if soft_edge && faded < 0.5 { f32::NAN } else { height });
+                coverage.push(faded);
+            }
+        }
+        Heightmap {
+            size,
+            scale: Vec2::new(0.5, 0.5),
+            samples,
+            colors: None,
+            coverage: soft_edge.then_some(coverage),
+            invert_y: false,
+            unit: Unit::Millimeter
+        }
+    }
+
+    fn masked(mask: Mask) -> Heightmap {
+        let mut heightmap = heightmap(false);
+        mask.apply(&mut heightmap).unwrap();
+        heightmap
+    }
+
+    /// Checks that every edge of the mesh is matched by one the other way round,
+    /// so that it is closed, and that it encloses some volume
+    fn assert_closed(heightmap: &Heightmap) {
+        let key = |v: Vec3| [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()];
+        let mut edges = HashMap::<([u32; 3], [u32; 3]), i32>::new();
+        let mut volume = 0.;
+        for_each_smooth_triangle(heightmap, |triangle, _surface| {
+            for k in 0..3 {
+                let (a, b) = (key(triangle[k]), key(triangle[(k + 1) % 3]));
+                *edges.entry((a, b)).or_default() += 1;
+                *edges.entry((b, a)).or_default() -= 1;
+            }
+            volume += triangle[0].dot(&triangle[1].cross(&triangle[2])) / 6.;
+        });
+        let unmatched = edges.values().filter(|count| **count != 0).count();
+        assert_eq!(unmatched, 0, "{} edges without a match", unmatched / 2);
+        assert!(volume > 0.);
+    }
+
+    #[test]
+    fn soft_alpha_edge_is_closed() {
+        assert_closed(&heightmap(true));
+    }
+
+    #[test]
+    fn polygon_touching_map_edge_is_closed() {
+        assert_closed(&masked(Mask { polygon: vec![[0., 0.], [30., 0.], [30., 30.]], ..Mask::default() }));
+    }
+
+    #[test]
+    fn polygon_covering_map_is_closed() {
+        assert_closed(&masked(Mask { polygon: vec![[0., 0.], [40., 0.], [40., 40.], [0., 40.]], ..Mask::default() }));
+    }
+
+    #[test]
+    fn arc_circle_is_closed() {
+        let path = "M 20 5 A 15 15 0 1 1 19.99 5 Z M 20 12 A 8 8 0 1 0 20.01 12 Z";
+        assert_closed(&masked(Mask { path: Some(path.to_string()), ..Mask::default() }));
+        assert_closed(&masked(Mask { path: Some(path.to_string()), invert: true, ..Mask::default() }));
+    }
+}
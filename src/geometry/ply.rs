@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::io::{Write, BufWriter};
+extern crate nalgebra_glm as glm;
+use glm::Vec3;
+use serde::{Deserialize, Serialize};
+use crate::geometry::triangle::*;
+
+type ColoredVertex = (Vec3, [u8; 3]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian
+}
+
+/// Merges vertices with the same position and color.
+///
+/// Returns the unique colored vertices and the vertex indices of each face.
+/// Faces that collapse to a line or point are dropped.
+fn index_colored_triangles(triangles: &[ColoredTriangle])
+-> (Vec<ColoredVertex>, Vec<[u32; 3]>) {
+    let mut vertices = Vec::<ColoredVertex>::new();
+    let mut faces = Vec::<[u32; 3]>::new();
+    let mut lookup = HashMap::<([u32; 3], [u8; 3]), u32>::new();
+    for (triangle, color) in triangles {
+        let mut face = [0u32; 3];
+        for (corner, vertex) in triangle.iter().enumerate() {
+            let key = (
+                [(vertex.x + 0.).to_bits(), (vertex.y + 0.).to_bits(), (vertex.z + 0.).to_bits()],
+                *color);
+            face[corner] = *lookup.entry(key).or_insert_with(|| {
+                vertices.push((*vertex, *color));
+                (vertices.len() - 1) as u32
+            });
+        }
+        if face[0] != face[1] && face[1] != face[2] && face[2] != face[0] {
+            faces.push(face);
+        }
+    }
+    (vertices, faces)
+}
+
+/// Writes colored triangles to a PLY file.
+///
+/// Each vertex carries the RGB color of the triangles it belongs to,
+/// so vertices shared by faces of different colors are duplicated.
+pub fn write_ply<W: Write>(
+    output: W,
+    triangles: &[ColoredTriangle],
+    format: PlyFormat)
+-> Result<(), std::io::Error> {
+    let mut output = BufWriter::new(output);
+    let (vertices, faces) = index_colored_triangles(triangles);
+    let format_name = match format {
+        PlyFormat::Ascii => "ascii",
+        PlyFormat::BinaryLittleEndian => "binary_little_endian"
+    };
+    writeln!(output, "ply")?;
+    writeln!(output, "format {} 1.0", format_name)?;
+    writeln!(output, "comment generated by heightmap_to_stl")?;
+    writeln!(output, "element vertex {}", vertices.len())?;
+    writeln!(output, "property float x")?;
+    writeln!(output, "property float y")?;
+    writeln!(output, "property float z")?;
+    writeln!(output, "property uchar red")?;
+    writeln!(output, "property uchar green")?;
+    writeln!(output, "property uchar blue")?;
+    writeln!(output, "element face {}", faces.len())?;
+    writeln!(output, "property list uchar int vertex_indices")?;
+    writeln!(output, "end_header")?;
+    match format {
+        PlyFormat::Ascii => {
+            for (position, color) in &vertices {
+                writeln!(output, "{} {} {} {} {} {}",
+                    position.x, position.y, position.z,
+                    color[0], color[1], color[2])?;
+            }
+            for face in &faces {
+                writeln!(output, "3 {} {} {}", face[0], face[1], face[2])?;
+            }
+        },
+        PlyFormat::BinaryLittleEndian => {
+            for (position, color) in &vertices {
+                output.write_all(&position.x.to_le_bytes())?;
+                output.write_all(&position.y.to_le_bytes())?;
+                output.write_all(&position.z.to_le_bytes())?;
+                output.write_all(color)?;
+            }
+            for face in &faces {
+                output.write_all(&[3u8])?;
+                for index in face {
+                    output.write_all(&(*index as i32).to_le_bytes())?;
+                }
+            }
+        }
+    }
+    output.flush()
+}
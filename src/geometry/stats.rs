@@ -0,0 +1,150 @@
+extern crate nalgebra_glm as glm;
+use glm::{Vec2, Vec3, TVec2, DVec3};
+use serde::Serialize;
+use crate::geometry::triangle::*;
+use crate::geometry::heightmap::Heightmap;
+use crate::geometry::units::Unit;
+
+/// Bytes in a binary STL file before the first triangle
+const STL_HEADER_SIZE: u64 = 84;
+
+/// Size, area and volume of a triangle mesh
+#[derive(Debug, Clone, Serialize)]
+pub struct MeshStats {
+    pub triangle_count: usize,
+    /// Smallest and largest corner of the bounding box of triangles with an area
+    pub bounds: Option<(Vec3, Vec3)>,
+    pub surface_area: f64,
+    /// Volume enclosed by the mesh.
+    /// Only meaningful for closed meshes with outward facing triangles.
+    pub volume: f64,
+    /// Size of the mesh as a binary STL file, in bytes
+    pub stl_size: u64
+}
+
+impl Default for MeshStats {
+    fn default() -> Self {
+        Self {
+            triangle_count: 0,
+            bounds: None,
+            surface_area: 0.,
+            volume: 0.,
+            stl_size: STL_HEADER_SIZE
+        }
+    }
+}
+
+impl MeshStats {
+    pub fn add(&mut self, triangle: &Triangle) {
+        self.triangle_count += 1;
+        self.stl_size += STL_FACET_SIZE as u64;
+        // f64, as large maps add up millions of small terms
+        let [a, b, c] = triangle.map(glm::convert::<Vec3, DVec3>);
+        let area = glm::cross(&(b - a), &(c - a)).norm() / 2.;
+        if area <= 0. {
+            return;
+        }
+        self.surface_area += area;
+        let (mut min, mut max) = self.bounds.unwrap_or((triangle[0], triangle[0]));
+        for vertex in triangle {
+            min = glm::min2(&min, vertex);
+            max = glm::max2(&max, vertex);
+        }
+        self.bounds = Some((min, max));
+        // Signed volume of the tetrahedron between the triangle and the origin
+        self.volume += glm::dot(&a, &glm::cross(&b, &c)) / 6.;
+    }
+}
+
+/// What a heightmap holds, and what its mesh will be
+#[derive(Debug, Clone, Serialize)]
+pub struct HeightmapStats {
+    pub size: TVec2<i32>,
+    pub scale: Vec2,
+    /// Unit of the scale, heights and mesh measurements
+    pub unit: Unit,
+    /// Lowest, highest and average of the samples that aren't NaN,
+    /// or none if every sample is
+    pub min_height: Option<f32>,
+    pub max_height: Option<f32>,
+    pub mean_height: Option<f32>,
+    /// Samples that are NaN
+    pub nan_count: usize,
+    /// Samples with no column in the mesh: NaN, zero or below
+    pub hole_count: usize,
+    pub mesh: MeshStats
+}
+
+/// Collects statistics of the heightmap's samples, and meshes it to measure the result
+pub fn heightmap_stats(heightmap: &Heightmap) -> HeightmapStats {
+    let mut min_height = f32::INFINITY;
+    let mut max_height = f32::NEG_INFINITY;
+    let mut sum = 0.;
+    let mut nan_count = 0;
+    let mut hole_count = 0;
+    for sample in &heightmap.samples {
+        if sample.is_nan() {
+            nan_count += 1;
+        } else {
+            min_height = min_height.min(*sample);
+            max_height = max_height.max(*sample);
+            sum += *sample as f64;
+        }
+        if sample.is_nan() || *sample <= 0. {
+            hole_count += 1;
+        }
+    }
+    let mut mesh = MeshStats::default();
+    heightmap.for_each_triangle(false, |triangle, _surface| mesh.add(&triangle));
+    let height_count = heightmap.samples.len() - nan_count;
+    let if_any = |height: f32| Some(height).filter(|_| height_count > 0);
+    HeightmapStats {
+        size: heightmap.size,
+        scale: heightmap.scale,
+        unit: heightmap.unit,
+        min_height: if_any(min_height),
+        max_height: if_any(max_height),
+        mean_height: if_any((sum / height_count as f64) as f32),
+        nan_count,
+        hole_count,
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heightmap(samples: Vec<f32>) -> Heightmap {
+        Heightmap {
+            size: TVec2::<i32>::new(2, 2),
+            scale: Vec2::new(1., 1.),
+            samples,
+            colors: None,
+            coverage: None,
+            invert_y: false,
+            unit: Unit::Millimeter
+        }
+    }
+
+    #[test]
+    fn heights_skip_nan_samples() {
+        let stats = heightmap_stats(&heightmap(vec![1., f32::NAN, 2., 6.]));
+        assert_eq!(stats.min_height, Some(1.));
+        assert_eq!(stats.max_height, Some(6.));
+        assert_eq!(stats.mean_height, Some(3.));
+        assert_eq!(stats.nan_count, 1);
+        assert_eq!(stats.hole_count, 1);
+    }
+
+    #[test]
+    fn all_nan_has_no_heights() {
+        let stats = heightmap_stats(&heightmap(vec![f32::NAN; 4]));
+        assert_eq!(stats.min_height, None);
+        assert_eq!(stats.max_height, None);
+        assert_eq!(stats.mean_height, None);
+        assert_eq!(stats.nan_count, 4);
+        assert_eq!(stats.hole_count, 4);
+        assert_eq!(stats.mesh.triangle_count, 0);
+    }
+}
@@ -0,0 +1,139 @@
+use std::io::{Write, Seek, BufWriter};
+use zip::ZipWriter;
+use zip::write::FileOptions;
+use crate::geometry::triangle::*;
+use crate::geometry::heightmap::Heightmap;
+use crate::geometry::units::Unit;
+
+/// Information stored alongside the mesh in a 3MF package.
+pub struct ThreeMfOptions {
+    /// Unit of one model coordinate
+    pub unit: Unit,
+    /// Name of the object (shown by most slicers)
+    pub name: String,
+    /// File the model was generated from
+    pub source: Option<String>,
+    /// Export settings, stored as `heightmap:<key>` metadata.
+    /// Keys must be valid XML names (no spaces).
+    pub parameters: Vec<(String, String)>
+}
+
+impl ThreeMfOptions {
+    /// Options in the heightmap's unit, with its size and scale
+    /// recorded as parameters.
+    pub fn for_heightmap(name: &str, source: Option<&str>, heightmap: &Heightmap) -> Self {
+        Self {
+            unit: heightmap.unit,
+            name: name.to_string(),
+            source: source.map(|s| s.to_string()),
+            parameters: vec![
+                ("size".to_string(),
+                    format!("{},{}", heightmap.size.x, heightmap.size.y)),
+                ("scale".to_string(),
+                    format!("{},{}", heightmap.scale.x, heightmap.scale.y))
+            ]
+        }
+    }
+}
+
+const CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">
+ <Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>
+ <Default Extension="model" ContentType="application/vnd.ms-package.3dmanufacturing-3dmodel+xml"/>
+</Types>
+"#;
+
+const RELATIONSHIPS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+ <Relationship Target="/3D/3dmodel.model" Id="rel0" Type="http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel"/>
+</Relationships>
+"#;
+
+/// Replaces characters that can't appear literally in XML text or attributes
+fn escape_xml(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            _ => result.push(c)
+        }
+    }
+    result
+}
+
+fn write_metadata<W: Write>(output: &mut W, name: &str, value: &str)
+-> Result<(), std::io::Error> {
+    writeln!(output, r#"  <metadata name="{}">{}</metadata>"#,
+        escape_xml(name), escape_xml(value))
+}
+
+/// Writes the 3D model part of a 3MF package
+fn write_model<W: Write>(
+    output: &mut W,
+    triangles: &[Triangle],
+    options: &ThreeMfOptions)
+-> Result<(), std::io::Error> {
+    let (vertices, indices) = index_triangles(triangles);
+    writeln!(output, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(output,
+        r#"<model unit="{}" xml:lang="en-US" xmlns="http://schemas.microsoft.com/3dmanufacturing/core/2015/02" xmlns:heightmap="https://github.com/Kenkron/Heightmap3D">"#,
+        options.unit.name())?;
+    write_metadata(output, "Title", &options.name)?;
+    write_metadata(output, "Application", "heightmap_to_stl")?;
+    if let Some(source) = &options.source {
+        write_metadata(output, "heightmap:source", source)?;
+    }
+    for (key, value) in &options.parameters {
+        write_metadata(output, &format!("heightmap:{}", key), value)?;
+    }
+    writeln!(output, " <resources>")?;
+    writeln!(output, r#"  <object id="1" name="{}" type="model">"#, escape_xml(&options.name))?;
+    writeln!(output, "   <mesh>")?;
+    writeln!(output, "    <vertices>")?;
+    for vertex in &vertices {
+        writeln!(output, r#"     <vertex x="{}" y="{}" z="{}"/>"#, vertex.x, vertex.y, vertex.z)?;
+    }
+    writeln!(output, "    </vertices>")?;
+    writeln!(output, "    <triangles>")?;
+    for face in &indices {
+        writeln!(output, r#"     <triangle v1="{}" v2="{}" v3="{}"/>"#, face[0], face[1], face[2])?;
+    }
+    writeln!(output, "    </triangles>")?;
+    writeln!(output, "   </mesh>")?;
+    writeln!(output, "  </object>")?;
+    writeln!(output, " </resources>")?;
+    writeln!(output, " <build>")?;
+    writeln!(output, r#"  <item objectid="1"/>"#)?;
+    writeln!(output, " </build>")?;
+    writeln!(output, "</model>")?;
+    Ok(())
+}
+
+/// Writes triangles to a 3MF package as a single object.
+///
+/// Unlike STL, 3MF records the unit of the coordinates,
+/// so slicers import the model at its intended size.
+pub fn write_3mf<W: Write + Seek>(
+    output: W,
+    triangles: &[Triangle],
+    options: &ThreeMfOptions)
+-> Result<(), std::io::Error> {
+    let mut archive = ZipWriter::new(output);
+    let file_options = FileOptions::default();
+    archive.start_file("[Content_Types].xml", file_options)?;
+    archive.write_all(CONTENT_TYPES.as_bytes())?;
+    archive.start_file("_rels/.rels", file_options)?;
+    archive.write_all(RELATIONSHIPS.as_bytes())?;
+    archive.start_file("3D/3dmodel.model", file_options)?;
+    {
+        let mut model = BufWriter::new(&mut archive);
+        write_model(&mut model, triangles, options)?;
+        model.flush()?;
+    }
+    archive.finish()?;
+    Ok(())
+}
@@ -0,0 +1,388 @@
+extern crate nalgebra_glm as glm;
+use glm::{Vec2, TVec2};
+use crate::geometry::heightmap::Heightmap;
+
+/// How to split a heightmap into tiles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileLayout {
+    /// A grid of this many columns (along x) and rows (along y)
+    Count { columns: u32, rows: u32 },
+    /// As few tiles as fit, joints included, within this width and depth.
+    /// Zero leaves a direction unsplit.
+    MaxSize(Vec2)
+}
+
+/// How neighbouring tiles fit together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Joint {
+    /// Straight edges
+    None,
+    /// Two round pins on a neck along each shared edge, like jigsaw pieces,
+    /// fitting into matching holes in the neighbouring tile
+    Pins,
+    /// A dovetail in the middle of each shared edge
+    Dovetail
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileOptions {
+    pub layout: TileLayout,
+    pub joint: Joint,
+    /// Width of a pin or dovetail.
+    /// If None, a quarter of the shorter side of a tile (or of the maximum size).
+    pub joint_size: Option<f32>,
+    /// Gap left around pins and dovetails, so that they fit into their holes
+    pub clearance: f32
+}
+
+impl Default for TileOptions {
+    fn default() -> Self {
+        Self {
+            layout: TileLayout::Count { columns: 1, rows: 1 },
+            joint: Joint::None,
+            joint_size: None,
+            clearance: 0.
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileError {
+    /// A tile would be narrower than one sample
+    TooManyTiles,
+    /// The maximum tile size leaves no room beside the joints
+    TooSmall
+}
+
+impl std::fmt::Display for TileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::TooManyTiles => write!(f, "More tiles than samples"),
+            Self::TooSmall => write!(f, "Maximum tile size is too small for the joints")
+        }
+    }
+}
+impl std::error::Error for TileError {}
+
+/// One tile: a closed solid of its own, starting at the origin
+pub struct Tile {
+    /// Position in the grid, from 0
+    pub column: u32,
+    pub row: u32,
+    pub heightmap: Heightmap
+}
+
+impl Joint {
+    /// How far a joint of width `size` reaches past the edge of its tile
+    fn depth(&self, size: f32) -> f32 {
+        match self {
+            Self::None => 0.,
+            Self::Pins => size,
+            Self::Dovetail => size / 2.
+        }
+    }
+
+    /// Positions of the joints along an edge, as fractions of its length
+    fn positions(&self) -> &'static [f32] {
+        match self {
+            Self::None => &[],
+            Self::Pins => &[0.25, 0.75],
+            Self::Dovetail => &[0.5]
+        }
+    }
+
+    /// Signed distance from a joint of width `size` to `p`, negative inside it.
+    ///
+    /// `p` is relative to the joint's base, with x along the edge,
+    /// and y away from the tile the joint belongs to.
+    fn distance(&self, size: f32, p: Vec2) -> f32 {
+        match self {
+            Self::None => f32::INFINITY,
+            Self::Pins => {
+                let radius = size / 2.;
+                let pin = (p - Vec2::new(0., radius)).norm() - radius;
+                let neck = rect_distance(Vec2::new(size / 4., radius), p - Vec2::new(0., radius / 2.));
+                pin.min(neck)
+            },
+            Self::Dovetail => polygon_distance(&[
+                Vec2::new(-size / 4., 0.),
+                Vec2::new(size / 4., 0.),
+                Vec2::new(size / 2., size / 2.),
+                Vec2::new(-size / 2., size / 2.)
+            ], p)
+        }
+    }
+}
+
+/// Signed distance from a rectangle centered on the origin, with `size` from side to side
+fn rect_distance(size: Vec2, p: Vec2) -> f32 {
+    let d = glm::abs(&p) - size / 2.;
+    Vec2::new(d.x.max(0.), d.y.max(0.)).norm() + d.x.max(d.y).min(0.)
+}
+
+/// Signed distance from a simple polygon, negative inside
+fn polygon_distance(polygon: &[Vec2], p: Vec2) -> f32 {
+    let mut distance = f32::INFINITY;
+    let mut inside = false;
+    for (k, a) in polygon.iter().enumerate() {
+        let b = &polygon[(k + 1) % polygon.len()];
+        let edge = b - a;
+        let t = (glm::dot(&(p - a), &edge) / edge.norm_squared()).clamp(0., 1.);
+        distance = distance.min((p - (a + edge * t)).norm());
+        if (a.y > p.y) != (b.y > p.y) && p.x < a.x + (p.y - a.y) / (b.y - a.y) * edge.x {
+            inside = !inside;
+        }
+    }
+    if inside { -distance } else { distance }
+}
+
+/// Where the tiles of a heightmap are, and which tile each sample belongs to
+struct Grid {
+    /// First sample of each column, then the width of the map
+    x_bounds: Vec<i32>,
+    /// First sample of each row, then the depth of the map
+    y_bounds: Vec<i32>,
+    scale: Vec2,
+    joint: Joint,
+    joint_size: f32,
+    clearance: f32
+}
+
+impl Grid {
+    fn new(heightmap: &Heightmap, options: &TileOptions) -> Result<Self, TileError> {
+        let extent = glm::vec2(heightmap.size.x as f32, heightmap.size.y as f32)
+            .component_mul(&heightmap.scale);
+        let (columns, rows, joint_size) = match options.layout {
+            TileLayout::Count { columns, rows } => {
+                let columns = columns.max(1);
+                let rows = rows.max(1);
+                let shorter = (extent.x / columns as f32).min(extent.y / rows as f32);
+                (columns, rows, options.joint_size.unwrap_or(shorter / 4.))
+            },
+            TileLayout::MaxSize(max) => {
+                let shorter = [max.x, max.y].into_iter()
+                    .filter(|side| *side > 0.)
+                    .fold(f32::INFINITY, f32::min);
+                // Without a limit there is a single tile, and no joints
+                let size = options.joint_size
+                    .unwrap_or(if shorter.is_finite() { shorter / 4. } else { 0. });
+                let depth = options.joint.depth(size) + options.clearance;
+                let count = |length: f32, max: f32| -> Result<u32, TileError> {
+                    if max <= 0. || length <= max {
+                        Ok(1)
+                    } else if max <= depth {
+                        Err(TileError::TooSmall)
+                    } else {
+                        Ok((length / (max - depth)).ceil() as u32)
+                    }
+                };
+                (count(extent.x, max.x)?, count(extent.y, max.y)?, size)
+            }
+        };
+        if columns as i32 > heightmap.size.x || rows as i32 > heightmap.size.y {
+            return Err(TileError::TooManyTiles);
+        }
+        let bounds = |count: u32, samples: i32| -> Vec<i32> {
+            (0..=count).map(|k| (k as i64 * samples as i64 / count as i64) as i32).collect()
+        };
+        Ok(Self {
+            x_bounds: bounds(columns, heightmap.size.x),
+            y_bounds: bounds(rows, heightmap.size.y),
+            scale: heightmap.scale,
+            joint: options.joint,
+            joint_size,
+            clearance: options.clearance
+        })
+    }
+
+    fn columns(&self) -> usize {
+        self.x_bounds.len() - 1
+    }
+
+    fn rows(&self) -> usize {
+        self.y_bounds.len() - 1
+    }
+
+    /// Samples that joints reach past the edge of their tile, along x and y
+    fn padding(&self) -> TVec2<i32> {
+        let depth = self.joint.depth(self.joint_size);
+        TVec2::new((depth / self.scale.x).ceil() as i32, (depth / self.scale.y).ceil() as i32)
+    }
+
+    /// Smallest distance from the joints along an edge to `p`.
+    /// The edge starts at `start` and ends at `end` (along x),
+    /// and `p` is relative to it, with y pointing into the neighbouring tile.
+    fn joint_distance(&self, start: f32, end: f32, p: Vec2) -> f32 {
+        self.joint.positions().iter()
+            .map(|position| {
+                let center = start + (end - start) * position;
+                self.joint.distance(self.joint_size, Vec2::new(p.x - center, p.y))
+            })
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    /// Tile (column, row) that the sample at (i, j) belongs to,
+    /// or None if it lies in the gap around a joint
+    fn owner(&self, i: i32, j: i32) -> Option<(usize, usize)> {
+        let column = self.x_bounds.partition_point(|bound| *bound <= i) - 1;
+        let row = self.y_bounds.partition_point(|bound| *bound <= j) - 1;
+        let p = Vec2::new((i as f32 + 0.5) * self.scale.x, (j as f32 + 0.5) * self.scale.y);
+        let x = |k: usize| self.x_bounds[k] as f32 * self.scale.x;
+        let y = |k: usize| self.y_bounds[k] as f32 * self.scale.y;
+        // Joints reaching in from the tile before this one, along x, then along y
+        let mut neighbours = Vec::new();
+        if column > 0 {
+            let distance = self.joint_distance(y(row), y(row + 1),
+                Vec2::new(p.y, p.x - x(column)));
+            neighbours.push(((column - 1, row), distance));
+        }
+        if row > 0 {
+            let distance = self.joint_distance(x(column), x(column + 1),
+                Vec2::new(p.x, p.y - y(row)));
+            neighbours.push(((column, row - 1), distance));
+        }
+        for (neighbour, distance) in neighbours {
+            if distance < 0. {
+                return Some(neighbour);
+            }
+            if distance < self.clearance {
+                return None;
+            }
+        }
+        Some((column, row))
+    }
+}
+
+/// Splits a heightmap into a grid of tiles, in rows from y = 0.
+///
+/// Joints are cut into the shared edges, each tile reaching into the next one
+/// along x and y. Samples of other tiles, and in the gaps around joints,
+/// become holes, so each tile is a closed solid with its own base.
+pub fn split_tiles(heightmap: &Heightmap, options: &TileOptions) -> Result<Vec<Tile>, TileError> {
+    let grid = Grid::new(heightmap, options)?;
+    let padding = grid.padding();
+    let mut tiles = Vec::new();
+    for row in 0..grid.rows() {
+        for column in 0..grid.columns() {
+            let start = TVec2::new(grid.x_bounds[column], grid.y_bounds[row]);
+            let end = TVec2::new(
+                (grid.x_bounds[column + 1] + padding.x).min(heightmap.size.x),
+                (grid.y_bounds[row + 1] + padding.y).min(heightmap.size.y));
+            let mut samples = Vec::new();
+            let mut colors = heightmap.colors.as_ref().map(|_| Vec::new());
+            let mut coverage = heightmap.coverage.as_ref().map(|_| Vec::new());
+            for j in start.y..end.y {
+                for i in start.x..end.x {
+                    samples.push(if grid.owner(i, j) == Some((column, row)) {
+                        heightmap.sample(i, j)
+                    } else {
+                        0.
+                    });
+                    if let Some(colors) = &mut colors {
+                        colors.extend(heightmap.color(i, j));
+                    }
+                    if let Some(coverage) = &mut coverage {
+                        coverage.extend(heightmap.coverage(i, j));
+                    }
+                }
+            }
+            tiles.push(Tile {
+                column: column as u32,
+                row: row as u32,
+                heightmap: Heightmap {
+                    size: end - start,
+                    scale: heightmap.scale,
+                    samples,
+                    colors,
+                    coverage,
+                    invert_y: false,
+                    unit: heightmap.unit
+                }
+            });
+        }
+    }
+    Ok(tiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::triangle::Triangle;
+    use crate::geometry::units::Unit;
+
+    /// A 30 by 20 heightmap of hills
+    fn heightmap() -> Heightmap {
+        let size = TVec2::new(30, 20);
+        Heightmap {
+            size,
+            scale: Vec2::new(0.5, 0.5),
+            samples: (0..size.x * size.y)
+                .map(|k| 2. + ((k % size.x) as f32 / 4.).sin() + ((k / size.x) as f32 / 3.).cos())
+                .collect(),
+            colors: None,
+            coverage: None,
+            invert_y: false,
+            unit: Unit::Millimeter
+        }
+    }
+
+    /// Volume of a tile's solid, checking that it is closed:
+    /// the faces of a closed solid add up to no area in any direction
+    fn closed_volume(triangles: &[Triangle]) -> f32 {
+        let mut area = glm::Vec3::zeros();
+        let mut volume = 0.;
+        for t in triangles {
+            area += (t[1] - t[0]).cross(&(t[2] - t[0])) / 2.;
+            volume += t[0].dot(&t[1].cross(&t[2])) / 6.;
+        }
+        assert!(area.norm() < 1e-3, "faces add up to {:?}", area);
+        volume
+    }
+
+    fn tile_volumes(joint: Joint, clearance: f32) -> f32 {
+        let options = TileOptions {
+            layout: TileLayout::Count { columns: 3, rows: 2 },
+            joint,
+            joint_size: None,
+            clearance
+        };
+        let tiles = split_tiles(&heightmap(), &options).unwrap();
+        assert_eq!(tiles.len(), 6);
+        tiles.iter().map(|tile| closed_volume(&tile.heightmap.get_triangles())).sum()
+    }
+
+    #[test]
+    fn tiles_add_up_to_the_map() {
+        let volume = closed_volume(&heightmap().get_triangles());
+        for joint in [Joint::None, Joint::Pins, Joint::Dovetail] {
+            let tiles = tile_volumes(joint, 0.);
+            assert!((tiles - volume).abs() < 1e-2, "{:?} tiles have {} of {}", joint, tiles, volume);
+        }
+    }
+
+    #[test]
+    fn joints_reach_into_the_next_tile() {
+        for joint in [Joint::Pins, Joint::Dovetail] {
+            let options = TileOptions {
+                layout: TileLayout::Count { columns: 3, rows: 2 },
+                joint,
+                ..TileOptions::default()
+            };
+            let tiles = split_tiles(&heightmap(), &options).unwrap();
+            // The first tile's own samples end at column 10 and row 10
+            let first = &tiles[0].heightmap;
+            assert!((0..10).any(|j| first.sample(10, j) > 0.), "{:?} joint along x", joint);
+            assert!((0..10).any(|i| first.sample(i, 10) > 0.), "{:?} joint along y", joint);
+            // And the next tiles have holes where they reach in
+            assert!((0..10).any(|j| tiles[1].heightmap.sample(0, j) == 0.));
+            assert!((0..10).any(|i| tiles[3].heightmap.sample(i, 0) == 0.));
+        }
+    }
+
+    #[test]
+    fn clearance_leaves_gaps_around_joints() {
+        let volume = closed_volume(&heightmap().get_triangles());
+        assert!(tile_volumes(Joint::Pins, 0.5) < volume - 1.);
+        assert!((tile_volumes(Joint::None, 0.5) - volume).abs() < 1e-2);
+    }
+}
@@ -1,9 +1,40 @@
-use std::fs::File;
-use std::io::{Write, Read, BufReader, BufWriter};
+use std::collections::HashMap;
+use std::io::{Write, Read, Seek, SeekFrom, BufReader, BufWriter};
 extern crate nalgebra_glm as glm;
 use glm::Vec3;
+use crate::geometry::StlError;
 
 pub type Triangle = [Vec3; 3];
+/// A triangle with an RGB color
+pub type ColoredTriangle = (Triangle, [u8; 3]);
+
+/// Merges identical vertices of a triangle list.
+///
+/// Returns the unique vertices, and the vertex indices of each triangle.
+/// Triangles that collapse to a line or point are dropped.
+pub fn index_triangles(triangles: &[Triangle]) -> (Vec<Vec3>, Vec<[u32; 3]>) {
+    let mut vertices = Vec::<Vec3>::new();
+    let mut indices = Vec::<[u32; 3]>::new();
+    let mut lookup = HashMap::<[u32; 3], u32>::new();
+    for triangle in triangles {
+        let mut face = [0u32; 3];
+        for (corner, vertex) in triangle.iter().enumerate() {
+            // Adding 0.0 turns -0.0 into 0.0 so both share a key
+            let key = [
+                (vertex.x + 0.).to_bits(),
+                (vertex.y + 0.).to_bits(),
+                (vertex.z + 0.).to_bits()];
+            face[corner] = *lookup.entry(key).or_insert_with(|| {
+                vertices.push(*vertex);
+                (vertices.len() - 1) as u32
+            });
+        }
+        if face[0] != face[1] && face[1] != face[2] && face[2] != face[0] {
+            indices.push(face);
+        }
+    }
+    (vertices, indices)
+}
 
 fn write_vec3<W: Write>(file: &mut W, vector: &Vec3)
 -> Result<(), std::io::Error>{
@@ -13,30 +44,166 @@ fn write_vec3<W: Write>(file: &mut W, vector: &Vec3)
     Ok(())
 }
 
-/// Writes triangles to a binary stl file.
+/// Writes triangles as a binary stl to any output (file, buffer, socket...).
 /// The normal is set based on the triangle vertices.
 /// Gives no data (0x00...) for header and attributes.
-pub fn write_stl_binary(
-    path: String,
-    triangles: &Vec::<Triangle>)
+pub fn write_stl_binary<W: Write>(
+    output: W,
+    triangles: &[Triangle])
 -> Result<(), std::io::Error> {
-    let mut output = BufWriter::new(File::create(path)?);
+    let mut output = BufWriter::new(output);
     output.write_all(&[0_u8; 80])?;
     output.write_all(&(triangles.len() as u32).to_le_bytes())?;
     for triangle in triangles {
-        let edge1 = triangle[1] - triangle[0];
-        let edge2 = triangle[2] - triangle[0];
-        let normal = glm::cross(&edge1, &edge2).normalize();
-        write_vec3(&mut output, &normal)?;
-        for vertex in triangle {
-            write_vec3(&mut output, vertex)?;
-        }
-        output.write_all(&[0_u8; 2])?;
+        write_facet(&mut output, triangle, 0)?;
     }
+    output.flush()
+}
+
+/// Bytes per facet (triangle) in a binary STL
+pub const STL_FACET_SIZE: usize = 50;
+
+/// Writes one binary STL facet, with the normal set based on the vertices
+/// (or zero, if the triangle has no area)
+pub fn write_facet<W: Write>(output: &mut W, triangle: &Triangle, attributes: u16)
+-> Result<(), std::io::Error> {
+    let edge1 = triangle[1] - triangle[0];
+    let edge2 = triangle[2] - triangle[0];
+    let cross_product = glm::cross(&edge1, &edge2);
+    let normal = if glm::dot(&cross_product, &cross_product) > 0. {
+        cross_product.normalize()
+    } else {
+        Vec3::zeros()
+    };
+    write_vec3(output, &normal)?;
+    for vertex in triangle {
+        write_vec3(output, vertex)?;
+    }
+    output.write_all(&attributes.to_le_bytes())?;
     Ok(())
 }
 
-fn read_vec3(buffer: &mut BufReader<File>) -> Result<Vec3, std::io::Error> {
+/// Packs a color into facet attribute bytes, using the VisCAM/SolidView
+/// convention: 5 bits each of blue, green and red (from the low bit up),
+/// with the high bit set to mark the color as valid.
+pub fn viscam_color(color: [u8; 3]) -> u16 {
+    let [red, green, blue] = color.map(|c| (c >> 3) as u16);
+    0x8000 | (red << 10) | (green << 5) | blue
+}
+
+/// The 80 byte header of a binary STL.
+///
+/// Text that doesn't fit is cut off, and the rest is filled with zeros.
+/// Text starting with "solid" is prefixed with a space,
+/// so readers don't mistake the file for an ASCII STL.
+pub fn stl_header(text: &str) -> [u8; 80] {
+    let mut header = [0_u8; 80];
+    let text = if text.starts_with("solid") {
+        format!(" {}", text)
+    } else {
+        text.to_string()
+    };
+    let length = text.len().min(80);
+    header[..length].copy_from_slice(&text.as_bytes()[..length]);
+    header
+}
+
+/// Writes a binary stl one triangle at a time.
+///
+/// The triangle count is patched into the header by `finish`,
+/// so the triangles never need to be held in memory.
+/// Outputs that can't seek back, such as standard output, are given the count
+/// up front instead (see `with_triangle_count`).
+pub struct StlWriter<W: Write> {
+    output: BufWriter<W>,
+    /// Where the file starts, if the triangle count is patched in by `finish`
+    start: Option<u64>,
+    /// Triangle count written in the header
+    header_count: u32,
+    triangle_count: u32
+}
+
+impl<W: Write + Seek> StlWriter<W> {
+    /// Writes an empty (0x00...) header with a placeholder triangle count
+    pub fn new(output: W) -> Result<Self, std::io::Error> {
+        Self::with_header(output, "")
+    }
+
+    /// Writes a header with the given text (see `stl_header`),
+    /// and a placeholder triangle count
+    pub fn with_header(mut output: W, header: &str) -> Result<Self, std::io::Error> {
+        let start = output.stream_position()?;
+        let mut writer = Self::with_triangle_count(output, header, 0)?;
+        writer.start = Some(start);
+        Ok(writer)
+    }
+
+    /// Fills in the triangle count, and returns the underlying output
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        let Some(start) = self.start else {
+            return self.finish_streamed();
+        };
+        let end = self.output.stream_position()?;
+        self.output.seek(SeekFrom::Start(start + 80))?;
+        self.output.write_all(&self.triangle_count.to_le_bytes())?;
+        self.output.seek(SeekFrom::Start(end))?;
+        self.output.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl<W: Write> StlWriter<W> {
+    /// Writes a header with the given text (see `stl_header`) and triangle count,
+    /// which must be the number of triangles that will be written
+    pub fn with_triangle_count(output: W, header: &str, triangle_count: u32)
+    -> Result<Self, std::io::Error> {
+        let mut output = BufWriter::new(output);
+        output.write_all(&stl_header(header))?;
+        output.write_all(&triangle_count.to_le_bytes())?;
+        Ok(Self { output, start: None, header_count: triangle_count, triangle_count: 0 })
+    }
+
+    /// Writes a triangle with no attribute data
+    pub fn write_triangle(&mut self, triangle: &Triangle) -> Result<(), std::io::Error> {
+        self.write_triangle_with_attributes(triangle, 0)
+    }
+
+    /// Writes a triangle, with its color in the attribute bytes (see `viscam_color`)
+    pub fn write_colored_triangle(&mut self, triangle: &Triangle, color: [u8; 3])
+    -> Result<(), std::io::Error> {
+        self.write_triangle_with_attributes(triangle, viscam_color(color))
+    }
+
+    pub fn write_triangle_with_attributes(&mut self, triangle: &Triangle, attributes: u16)
+    -> Result<(), std::io::Error> {
+        write_facet(&mut self.output, triangle, attributes)?;
+        self.triangle_count += 1;
+        Ok(())
+    }
+
+    /// Writes facets already encoded by `write_facet`
+    pub fn write_facets(&mut self, facets: &[u8]) -> Result<(), std::io::Error> {
+        self.output.write_all(facets)?;
+        self.triangle_count += (facets.len() / STL_FACET_SIZE) as u32;
+        Ok(())
+    }
+
+    /// Number of triangles written so far
+    pub fn triangle_count(&self) -> u32 {
+        self.triangle_count
+    }
+
+    /// Checks that as many triangles were written as the header says,
+    /// and returns the underlying output
+    pub fn finish_streamed(self) -> Result<W, std::io::Error> {
+        if self.triangle_count != self.header_count {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!(
+                "STL header gives {} triangles, but {} were written", self.header_count, self.triangle_count)));
+        }
+        self.output.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+fn read_vec3<R: Read>(buffer: &mut R) -> Result<Vec3, std::io::Error> {
     let mut bytes = [0u8; 4];
     buffer.read_exact(&mut bytes)?;
     let x = f32::from_le_bytes(bytes);
@@ -47,13 +214,14 @@ fn read_vec3(buffer: &mut BufReader<File>) -> Result<Vec3, std::io::Error> {
     Ok(Vec3::new(x, y, z))
 }
 
-/// Loads a binary STL file into a list of triangles
+/// Loads a binary STL from any input (file, buffer, socket...)
+/// into a list of triangles
 ///
 /// Discards header, normals, and attributes
-pub fn read_stl_binary(path: &str) -> Result<Vec::<Triangle>, std::io::Error> {
+pub fn read_stl_binary<R: Read>(input: R) -> Result<Vec::<Triangle>, std::io::Error> {
     let mut header = [0u8; 80];
     let mut triangles = Vec::<Triangle>::new();
-    let mut input = BufReader::new(File::open(path)?);
+    let mut input = BufReader::new(input);
     input.read_exact(&mut header)?;
     let mut bytes = [0u8; 4];
     input.read_exact(&mut bytes)?;
@@ -68,4 +236,351 @@ pub fn read_stl_binary(path: &str) -> Result<Vec::<Triangle>, std::io::Error> {
         input.read_exact(&mut attribute_bytes)?;
     }
     Ok(triangles)
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StlFormat {
+    Ascii,
+    Binary
+}
+
+/// What to do with the normals stored in an STL file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalMode {
+    /// Keep the stored normals as they are
+    Keep,
+    /// Replace the stored normals with ones computed from the vertex winding
+    Recompute,
+    /// Fail if a stored normal is more than `max_degrees` away from
+    /// the normal given by the vertex winding.
+    /// Zero normals, and normals of zero area triangles, are not checked.
+    Check { max_degrees: f32 }
+}
+
+/// The contents of an STL file
+pub struct StlMesh {
+    pub format: StlFormat,
+    /// Binary header, or the solid name of an ASCII file
+    pub header: String,
+    pub triangles: Vec<Triangle>,
+    /// Normal of each triangle
+    pub normals: Vec<Vec3>
+}
+
+/// Loads an ASCII or binary STL, detecting which it is.
+///
+/// Binary files must be exactly as long as their triangle count requires.
+/// Coordinates must be finite, and normals are handled according to `normals`.
+pub fn read_stl<R: Read + Seek>(mut input: R, normals: NormalMode)
+-> Result<StlMesh, StlError> {
+    let start = input.stream_position()?;
+    let length = input.seek(SeekFrom::End(0))? - start;
+    input.seek(SeekFrom::Start(start))?;
+    let mut input = BufReader::new(input);
+
+    let mut prefix = Vec::<u8>::new();
+    (&mut input).take(84).read_to_end(&mut prefix)?;
+    let expected = if prefix.len() == 84 {
+        let count = u32::from_le_bytes([prefix[80], prefix[81], prefix[82], prefix[83]]);
+        Some(84 + 50 * count as u64)
+    } else {
+        None
+    };
+    let looks_ascii = String::from_utf8_lossy(&prefix).trim_start().starts_with("solid");
+    let length_error = || {
+        let expected = expected.unwrap_or(84);
+        if length < expected {
+            StlError::Truncated { expected, actual: length }
+        } else {
+            StlError::TrailingData { expected, actual: length }
+        }
+    };
+
+    let mut mesh = if expected == Some(length) {
+        read_stl_binary_body(&mut input, &prefix)?
+    } else if looks_ascii {
+        let mut text = prefix;
+        input.read_to_end(&mut text)?;
+        // Some binary files also start with "solid"
+        if !is_text(&text) {
+            return Err(length_error());
+        }
+        read_stl_ascii(&String::from_utf8_lossy(&text))?
+    } else {
+        return Err(length_error());
+    };
+
+    for (facet, (triangle, normal)) in mesh.triangles.iter().zip(mesh.normals.iter_mut()).enumerate() {
+        let finite = |v: &Vec3| v.iter().all(|c| c.is_finite());
+        if !triangle.iter().all(finite) || !finite(normal) {
+            return Err(StlError::NonFinite { facet });
+        }
+        let winding = glm::cross(&(triangle[1] - triangle[0]), &(triangle[2] - triangle[0]));
+        let has_area = glm::dot(&winding, &winding) > 0.;
+        match normals {
+            NormalMode::Keep => {},
+            NormalMode::Recompute => {
+                *normal = if has_area { winding.normalize() } else { Vec3::zeros() };
+            },
+            NormalMode::Check { max_degrees } => {
+                if has_area && glm::dot(normal, normal) > 0. {
+                    let degrees = glm::angle(normal, &winding).to_degrees();
+                    if degrees > max_degrees {
+                        return Err(StlError::NormalMismatch { facet, degrees });
+                    }
+                }
+            }
+        }
+    }
+    Ok(mesh)
+}
+
+/// True if `bytes` has no control characters other than whitespace,
+/// as binary facets almost always do
+fn is_text(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&byte| !byte.is_ascii_control() || byte.is_ascii_whitespace())
+}
+
+/// Reads the facets of a binary STL whose 84 byte header is in `prefix`
+fn read_stl_binary_body<R: Read>(input: &mut R, prefix: &[u8]) -> Result<StlMesh, StlError> {
+    let triangle_count = u32::from_le_bytes([prefix[80], prefix[81], prefix[82], prefix[83]]);
+    let header = String::from_utf8_lossy(&prefix[..80])
+        .trim_end_matches(['\0', ' '])
+        .to_string();
+    let mut triangles = Vec::<Triangle>::with_capacity(triangle_count as usize);
+    let mut normals = Vec::<Vec3>::with_capacity(triangle_count as usize);
+    let mut attribute_bytes = [0u8; 2];
+    for _i in 0..triangle_count {
+        normals.push(read_vec3(input)?);
+        triangles.push([
+            read_vec3(input)?,
+            read_vec3(input)?,
+            read_vec3(input)?]);
+        input.read_exact(&mut attribute_bytes)?;
+    }
+    Ok(StlMesh { format: StlFormat::Binary, header, triangles, normals })
+}
+
+/// Whitespace separated words of an ASCII STL, with their line numbers
+struct Tokens<'a> {
+    tokens: Vec<(usize, &'a str)>,
+    position: usize
+}
+
+impl<'a> Tokens<'a> {
+    fn new(text: &'a str) -> Self {
+        let tokens = text.lines().enumerate()
+            .flat_map(|(line, words)| words.split_whitespace().map(move |word| (line + 1, word)))
+            .collect();
+        Self { tokens, position: 0 }
+    }
+
+    fn peek(&self) -> Option<(usize, &'a str)> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn line(&self) -> usize {
+        match self.peek() {
+            Some((line, _)) => line,
+            None => self.tokens.last().map_or(1, |(line, _)| *line)
+        }
+    }
+
+    fn error(&self, message: String) -> StlError {
+        StlError::Syntax { line: self.line(), message }
+    }
+
+    fn next(&mut self, expected: &str) -> Result<(usize, &'a str), StlError> {
+        let token = self.peek()
+            .ok_or_else(|| self.error(format!("expected '{}', found end of file", expected)))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), StlError> {
+        let (line, word) = self.next(keyword)?;
+        if word.eq_ignore_ascii_case(keyword) {
+            Ok(())
+        } else {
+            Err(StlError::Syntax { line, message: format!("expected '{}', found '{}'", keyword, word) })
+        }
+    }
+
+    fn vec3(&mut self) -> Result<Vec3, StlError> {
+        let mut result = Vec3::zeros();
+        for i in 0..3 {
+            let (line, word) = self.next("number")?;
+            result[i] = word.parse().map_err(|_| StlError::Syntax {
+                line, message: format!("expected number, found '{}'", word) })?;
+        }
+        Ok(result)
+    }
+
+    /// Skips the rest of the words on `line` (such as a solid's name)
+    fn rest_of_line(&mut self, line: usize) -> String {
+        let mut words = Vec::<&str>::new();
+        while let Some((next_line, word)) = self.peek() {
+            if next_line != line {
+                break;
+            }
+            words.push(word);
+            self.position += 1;
+        }
+        words.join(" ")
+    }
+}
+
+/// Parses an ASCII STL, which may contain several solids
+fn read_stl_ascii(text: &str) -> Result<StlMesh, StlError> {
+    let mut tokens = Tokens::new(text);
+    let mut header = None;
+    let mut triangles = Vec::<Triangle>::new();
+    let mut normals = Vec::<Vec3>::new();
+    loop {
+        let line = tokens.line();
+        tokens.keyword("solid")?;
+        let name = tokens.rest_of_line(line);
+        header.get_or_insert(name);
+        loop {
+            match tokens.peek() {
+                Some((line, word)) if word.eq_ignore_ascii_case("endsolid") => {
+                    tokens.position += 1;
+                    tokens.rest_of_line(line);
+                    break;
+                },
+                Some(_) => {},
+                None => return Err(tokens.error(
+                    "expected 'facet' or 'endsolid', found end of file".to_string()))
+            }
+            tokens.keyword("facet")?;
+            tokens.keyword("normal")?;
+            normals.push(tokens.vec3()?);
+            tokens.keyword("outer")?;
+            tokens.keyword("loop")?;
+            let mut triangle = [Vec3::zeros(); 3];
+            for vertex in triangle.iter_mut() {
+                tokens.keyword("vertex")?;
+                *vertex = tokens.vec3()?;
+            }
+            triangles.push(triangle);
+            tokens.keyword("endloop")?;
+            tokens.keyword("endfacet")?;
+        }
+        if tokens.peek().is_none() {
+            break;
+        }
+    }
+    Ok(StlMesh {
+        format: StlFormat::Ascii,
+        header: header.unwrap_or_default(),
+        triangles,
+        normals
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    const TRIANGLE: Triangle = [
+        Vec3::new(0., 0., 0.),
+        Vec3::new(1., 0., 0.),
+        Vec3::new(0., 1., 0.)
+    ];
+
+    /// A binary STL with the given header, and one facet per (normal, triangle)
+    fn binary(header: &[u8], facets: &[(Vec3, Triangle)]) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        bytes.resize(80, 0);
+        bytes.extend((facets.len() as u32).to_le_bytes());
+        for (normal, triangle) in facets {
+            write_vec3(&mut bytes, normal).unwrap();
+            for vertex in triangle {
+                write_vec3(&mut bytes, vertex).unwrap();
+            }
+            bytes.extend([0, 0]);
+        }
+        bytes
+    }
+
+    fn read(bytes: Vec<u8>, normals: NormalMode) -> Result<StlMesh, StlError> {
+        read_stl(Cursor::new(bytes), normals)
+    }
+
+    #[test]
+    fn reads_binary_and_ascii() {
+        let mesh = read(binary(b"heights", &[(Vec3::z(), TRIANGLE)]), NormalMode::Keep).unwrap();
+        assert_eq!(mesh.format, StlFormat::Binary);
+        assert_eq!(mesh.header, "heights");
+        assert_eq!(mesh.triangles, vec![TRIANGLE]);
+        let text = "solid heights\n facet normal 0 0 1\n  outer loop\n   vertex 0 0 0\n   vertex 1 0 0\n   \
+            vertex 0 1 0\n  endloop\n endfacet\nendsolid heights\n";
+        let mesh = read(text.as_bytes().to_vec(), NormalMode::Keep).unwrap();
+        assert_eq!(mesh.format, StlFormat::Ascii);
+        assert_eq!(mesh.header, "heights");
+        assert_eq!(mesh.triangles, vec![TRIANGLE]);
+    }
+
+    #[test]
+    fn truncated_binary_is_an_error() {
+        let mut bytes = binary(b"", &[(Vec3::z(), TRIANGLE); 2]);
+        bytes.truncate(bytes.len() - 10);
+        assert!(matches!(read(bytes, NormalMode::Keep),
+            Err(StlError::Truncated { expected: 184, actual: 174 })));
+    }
+
+    #[test]
+    fn trailing_data_is_an_error() {
+        let mut bytes = binary(b"", &[(Vec3::z(), TRIANGLE)]);
+        bytes.extend(b"extra");
+        assert!(matches!(read(bytes, NormalMode::Keep),
+            Err(StlError::TrailingData { expected: 134, actual: 139 })));
+    }
+
+    #[test]
+    fn binary_starting_with_solid_is_read_as_binary() {
+        let bytes = binary(b"solid heights", &[(Vec3::z(), TRIANGLE)]);
+        assert_eq!(read(bytes.clone(), NormalMode::Keep).unwrap().format, StlFormat::Binary);
+        // With the wrong length, it isn't taken for ASCII with a syntax error
+        let mut truncated = bytes.clone();
+        truncated.pop();
+        assert!(matches!(read(truncated, NormalMode::Keep), Err(StlError::Truncated { .. })));
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(matches!(read(trailing, NormalMode::Keep), Err(StlError::TrailingData { .. })));
+    }
+
+    #[test]
+    fn ascii_syntax_error_gives_the_line() {
+        let text = "solid\n facet normal 0 0 1\n  outer loop\n   vertex 0 0 zero\n";
+        assert!(matches!(read(text.as_bytes().to_vec(), NormalMode::Keep),
+            Err(StlError::Syntax { line: 4, .. })));
+    }
+
+    #[test]
+    fn non_finite_coordinates_are_an_error() {
+        let mut triangle = TRIANGLE;
+        triangle[2].y = f32::NAN;
+        let bytes = binary(b"", &[(Vec3::z(), TRIANGLE), (Vec3::z(), triangle)]);
+        assert!(matches!(read(bytes, NormalMode::Keep), Err(StlError::NonFinite { facet: 1 })));
+        let bytes = binary(b"", &[(Vec3::new(0., f32::INFINITY, 0.), TRIANGLE)]);
+        assert!(matches!(read(bytes, NormalMode::Keep), Err(StlError::NonFinite { facet: 0 })));
+    }
+
+    #[test]
+    fn normals_against_the_winding() {
+        // The winding gives +z
+        let bytes = binary(b"", &[(-Vec3::z(), TRIANGLE)]);
+        assert_eq!(read(bytes.clone(), NormalMode::Keep).unwrap().normals, vec![-Vec3::z()]);
+        assert_eq!(read(bytes.clone(), NormalMode::Recompute).unwrap().normals, vec![Vec3::z()]);
+        match read(bytes.clone(), NormalMode::Check { max_degrees: 10. }) {
+            Err(StlError::NormalMismatch { facet: 0, degrees }) => assert!((degrees - 180.).abs() < 0.01),
+            _ => panic!("expected a normal mismatch")
+        }
+        assert!(read(bytes, NormalMode::Check { max_degrees: 180. }).is_ok());
+        // Zero normals aren't checked
+        let bytes = binary(b"", &[(Vec3::zeros(), TRIANGLE)]);
+        assert!(read(bytes, NormalMode::Check { max_degrees: 10. }).is_ok());
+    }
+}
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// Length unit of model coordinates (the heightmap's scale and heights)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Unit {
+    #[serde(rename = "um")]
+    Micron,
+    #[default]
+    #[serde(rename = "mm")]
+    Millimeter,
+    #[serde(rename = "cm")]
+    Centimeter,
+    #[serde(rename = "in")]
+    Inch,
+    #[serde(rename = "ft")]
+    Foot,
+    #[serde(rename = "m")]
+    Meter
+}
+
+impl Unit {
+    /// Full name, as used by the `unit` attribute of 3MF models
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Micron => "micron",
+            Self::Millimeter => "millimeter",
+            Self::Centimeter => "centimeter",
+            Self::Inch => "inch",
+            Self::Foot => "foot",
+            Self::Meter => "meter"
+        }
+    }
+
+    pub fn abbreviation(&self) -> &'static str {
+        match self {
+            Self::Micron => "um",
+            Self::Millimeter => "mm",
+            Self::Centimeter => "cm",
+            Self::Inch => "in",
+            Self::Foot => "ft",
+            Self::Meter => "m"
+        }
+    }
+
+    /// Length of one unit in millimeters
+    pub fn millimeters(&self) -> f32 {
+        match self {
+            Self::Micron => 0.001,
+            Self::Millimeter => 1.,
+            Self::Centimeter => 10.,
+            Self::Inch => 25.4,
+            Self::Foot => 304.8,
+            Self::Meter => 1000.
+        }
+    }
+
+    /// Converts a length in this unit to `unit`
+    pub fn convert(&self, length: f32, unit: Unit) -> f32 {
+        length * self.millimeters() / unit.millimeters()
+    }
+}
@@ -0,0 +1,378 @@
+use std::f32::consts::PI;
+use std::io::Write;
+extern crate nalgebra_glm as glm;
+use glm::{Vec2, Vec3, TVec2};
+use serde::{Deserialize, Serialize};
+use crate::geometry::triangle::*;
+use crate::geometry::heightmap::*;
+
+/// Surface that a heightmap's solid can be bent around.
+///
+/// Heights are displaced along the surface's normal, outwards,
+/// from the surface at height 0. Shapes that close on themselves
+/// join the left and right edges of the heightmap in a seam.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WrapShape {
+    /// Part of a cylinder around the z axis, centered on the -y side.
+    /// The heightmap's y becomes z.
+    Arc,
+    /// A full cylinder around the z axis, with the seam on the +y side.
+    /// The heightmap's y becomes z.
+    Cylinder,
+    /// A sphere around the origin, with x as longitude and y as latitude
+    /// (so maps twice as wide as they are deep keep their proportions).
+    /// The first and last rows meet at the poles.
+    Sphere,
+    /// A curve in the xy plane (see `Wrap::points`), which the heightmap's x
+    /// is stretched along. The heightmap's y becomes z.
+    Curve
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Wrap {
+    pub shape: WrapShape,
+    /// Angle covered by an arc, in degrees (less than 360)
+    pub angle: f32,
+    /// Points of the curve, in order. Heights point to the right of it,
+    /// so outwards for a curve going counterclockwise.
+    /// The curve is closed if the last point is the first.
+    pub points: Vec<[f32; 2]>
+}
+
+impl Default for Wrap {
+    fn default() -> Self {
+        Self { shape: WrapShape::Arc, angle: 120., points: Vec::new() }
+    }
+}
+
+/// Cuts a triangle along the lines x = k * `step`, so that no piece crosses one.
+/// Points on the lines are placed exactly on them.
+fn slice_x<F: FnMut(Triangle)>(triangle: Triangle, step: f32, emit: &mut F) {
+    let min = triangle.iter().map(|vertex| vertex.x).fold(f32::INFINITY, f32::min);
+    let max = triangle.iter().map(|vertex| vertex.x).fold(f32::NEG_INFINITY, f32::max);
+    let first = (min / step).floor() as i32;
+    let last = (max / step).ceil() as i32;
+    if last - first <= 1 {
+        emit(triangle);
+        return;
+    }
+    for k in first..last {
+        let polygon = clip_x(&triangle, k as f32 * step, true);
+        let polygon = clip_x(&polygon, (k + 1) as f32 * step, false);
+        for index in 2..polygon.len() {
+            emit([polygon[0], polygon[index - 1], polygon[index]]);
+        }
+    }
+}
+
+/// Keeps the part of a convex polygon on one side of the line x = `line`
+/// (the side above it if `above`)
+fn clip_x(polygon: &[Vec3], line: f32, above: bool) -> Vec<Vec3> {
+    let inside = |vertex: &Vec3| if above { vertex.x >= line } else { vertex.x <= line };
+    let mut result = Vec::new();
+    for (index, a) in polygon.iter().enumerate() {
+        let b = &polygon[(index + 1) % polygon.len()];
+        if inside(a) {
+            result.push(*a);
+        }
+        if inside(a) != inside(b) && a.x != line && b.x != line {
+            // Interpolated in x order, so that both triangles sharing an edge get the same point
+            let (low, high) = if a.x < b.x { (a, b) } else { (b, a) };
+            let mut point = low + (high - low) * ((line - low.x) / (high.x - low.x));
+            point.x = line;
+            result.push(point);
+        }
+    }
+    result
+}
+
+/// A point along a curve, with its normal, at `length` from the start
+struct CurvePoint {
+    length: f32,
+    point: Vec2,
+    normal: Vec2
+}
+
+/// The curve's points, with their distance along it and their normals
+/// (averaged between the segments on either side)
+fn curve_points(points: &[[f32; 2]], closed: bool) -> Vec<CurvePoint> {
+    let points: Vec<Vec2> = points.iter().map(|[x, y]| Vec2::new(*x, *y)).collect();
+    let segment_normal = |k: usize| {
+        let tangent = (points[k + 1] - points[k]).normalize();
+        Vec2::new(tangent.y, -tangent.x)
+    };
+    let last = points.len() - 1;
+    let mut length = 0.;
+    let mut result = Vec::new();
+    for k in 0..points.len() {
+        if k > 0 {
+            length += (points[k] - points[k - 1]).norm();
+        }
+        let before = if k > 0 { Some(segment_normal(k - 1)) }
+            else if closed { Some(segment_normal(last - 1)) }
+            else { None };
+        let after = if k < last { Some(segment_normal(k)) }
+            else if closed { Some(segment_normal(0)) }
+            else { None };
+        let normal = match (before, after) {
+            (Some(before), Some(after)) => (before + after).normalize(),
+            (Some(normal), None) | (None, Some(normal)) => normal,
+            (None, None) => Vec2::new(0., -1.)
+        };
+        result.push(CurvePoint { length, point: points[k], normal });
+    }
+    result
+}
+
+/// How points of a particular heightmap's flat solid are moved by a `Wrap`
+struct Mapping<'a> {
+    wrap: &'a Wrap,
+    size: TVec2<i32>,
+    scale: Vec2,
+    /// Radius of the surface, for arcs, cylinders and spheres
+    radius: f32,
+    /// Angle of the left edge, for arcs, cylinders and spheres
+    start: f32,
+    curve: Vec<CurvePoint>
+}
+
+impl<'a> Mapping<'a> {
+    fn new(wrap: &'a Wrap, heightmap: &Heightmap) -> Self {
+        let width = heightmap.size.x as f32 * heightmap.scale.x;
+        let (angle, start) = match wrap.shape {
+            WrapShape::Arc => {
+                let angle = wrap.angle.to_radians();
+                // The middle of the heightmap faces -y
+                (angle, -(PI + angle) / 2.)
+            },
+            WrapShape::Cylinder | WrapShape::Sphere | WrapShape::Curve => (2. * PI, PI / 2.)
+        };
+        let curve = match wrap.shape {
+            WrapShape::Curve => curve_points(&wrap.points, wrap.is_closed()),
+            _ => Vec::new()
+        };
+        Self {
+            wrap,
+            size: heightmap.size,
+            scale: heightmap.scale,
+            radius: width / angle,
+            start,
+            curve
+        }
+    }
+
+    /// Position of a point of the flat solid once wrapped.
+    ///
+    /// Points are snapped to the sample grid (which all the solid's vertices are on),
+    /// so that vertices shared by neighbouring faces end up in exactly the same place.
+    fn point(&self, point: Vec3) -> Vec3 {
+        let mut i = (point.x / self.scale.x).round();
+        if self.wrap.is_closed() && i as i32 == self.size.x {
+            i = 0.;
+        }
+        let j = (point.y / self.scale.y).round();
+        let x = i * self.scale.x;
+        let y = j * self.scale.y;
+        let r = self.radius + point.z;
+        match self.wrap.shape {
+            WrapShape::Arc | WrapShape::Cylinder => {
+                let phi = self.start + x / self.radius;
+                Vec3::new(r * phi.cos(), r * phi.sin(), y)
+            },
+            WrapShape::Sphere => {
+                // The poles are exact, so that the faces meeting there share vertices
+                if j as i32 <= 0 {
+                    return Vec3::new(0., 0., -r);
+                }
+                if j as i32 >= self.size.y {
+                    return Vec3::new(0., 0., r);
+                }
+                let longitude = self.start + x / self.radius;
+                let latitude = (j / self.size.y as f32 - 0.5) * PI;
+                Vec3::new(
+                    r * latitude.cos() * longitude.cos(),
+                    r * latitude.cos() * longitude.sin(),
+                    r * latitude.sin())
+            },
+            WrapShape::Curve => {
+                let total = self.curve.last().map_or(0., |last| last.length);
+                let length = x / (self.size.x as f32 * self.scale.x) * total;
+                let after = self.curve.partition_point(|point| point.length < length)
+                    .clamp(1, self.curve.len() - 1);
+                let (a, b) = (&self.curve[after - 1], &self.curve[after]);
+                let t = if b.length > a.length { (length - a.length) / (b.length - a.length) } else { 0. };
+                let normal = glm::lerp(&a.normal, &b.normal, t).normalize();
+                let p = glm::lerp(&a.point, &b.point, t) + normal * point.z;
+                Vec3::new(p.x, p.y, y)
+            }
+        }
+    }
+}
+
+impl Wrap {
+    /// True if the shape closes on itself, joining the left and right edges of the heightmap
+    pub fn is_closed(&self) -> bool {
+        match self.shape {
+            WrapShape::Arc => false,
+            WrapShape::Cylinder | WrapShape::Sphere => true,
+            WrapShape::Curve => self.points.len() > 2 && self.points.first() == self.points.last()
+        }
+    }
+
+    /// Checks that the shape can be wrapped around
+    pub fn validate(&self) -> Result<(), String> {
+        match self.shape {
+            WrapShape::Arc if self.angle <= 0. || self.angle >= 360. =>
+                Err("the angle of an arc must be between 0 and 360 degrees".to_string()),
+            WrapShape::Curve if self.points.len() < 2 =>
+                Err("a curve needs at least two points".to_string()),
+            WrapShape::Curve if self.points.windows(2).any(|pair| pair[0] == pair[1]) =>
+                Err("a curve can't repeat a point twice in a row".to_string()),
+            _ => Ok(())
+        }
+    }
+
+    /// Passes each triangle of the wrapped solid to `emit`,
+    /// along with the flat triangle it came from, and its surface.
+    ///
+    /// Faces are cut at every sample along x, so that they follow the curve,
+    /// and combined as in `Heightmap::for_each_triangle`.
+    pub fn for_each_triangle<F>(&self, heightmap: &Heightmap, by_color: bool, mut emit: F)
+    where F: FnMut(Triangle, Triangle, Surface) {
+        let mapping = Mapping::new(self, heightmap);
+        let width = heightmap.size.x as f32 * heightmap.scale.x;
+        let mut add = |triangle: Triangle, surface: Surface| {
+            slice_x(triangle, heightmap.scale.x, &mut |flat: Triangle| {
+                let wrapped = flat.map(|vertex| mapping.point(vertex));
+                // Corners meeting at a sphere's poles, or snapped to the same sample,
+                // leave a triangle with no area (and the rest of the solid closed without it)
+                if wrapped[0] != wrapped[1] && wrapped[1] != wrapped[2] && wrapped[2] != wrapped[0] {
+                    emit(wrapped, flat, surface);
+                }
+            });
+        };
+        heightmap.for_each_triangle(by_color, |triangle, surface| {
+            // The walls at the left and right edges of a closed shape would meet face to face
+            let on_seam = |x: f32| triangle.iter().all(|vertex| vertex.x == x);
+            if surface == Surface::Wall && self.is_closed() && (on_seam(0.) || on_seam(width)) {
+                return;
+            }
+            add(triangle, surface);
+        });
+        if self.is_closed() {
+            // Instead, one wall joins the last column to the first
+            // (except where they are the same height, as between samples)
+            for j in 0..heightmap.size.y {
+                let left_z = heightmap.sample(heightmap.size.x - 1, j);
+                let z = heightmap.sample(0, j);
+                if left_z == z {
+                    continue;
+                }
+                let y = j as f32 * heightmap.scale.y;
+                let top = (j + 1) as f32 * heightmap.scale.y;
+                let corners = [
+                    Vec3::new(width, top, left_z),
+                    Vec3::new(width, y, left_z),
+                    Vec3::new(width, y, z),
+                    Vec3::new(width, top, z)
+                ];
+                add([corners[0], corners[1], corners[2]], Surface::Wall);
+                add([corners[0], corners[2], corners[3]], Surface::Wall);
+            }
+        }
+    }
+
+    pub fn get_triangles(&self, heightmap: &Heightmap) -> Vec<Triangle> {
+        let mut result = Vec::new();
+        self.for_each_triangle(heightmap, false, |triangle, _flat, _surface| result.push(triangle));
+        result
+    }
+
+    /// Like `Heightmap::get_colored_triangles`, but wrapped
+    pub fn get_colored_triangles(&self, heightmap: &Heightmap, wall_color: [u8; 3], base_color: [u8; 3])
+    -> Vec<ColoredTriangle> {
+        let mut result = Vec::new();
+        self.for_each_triangle(heightmap, true, |triangle, _flat, surface| {
+            result.push((triangle, heightmap.surface_color(surface, wall_color, base_color)));
+        });
+        result
+    }
+
+    /// The flat triangles that those of `get_colored_triangles` came from, in the same order,
+    /// so that textures of the heightmap can be draped over the wrapped solid
+    pub fn get_flat_triangles(&self, heightmap: &Heightmap) -> Vec<Triangle> {
+        let mut result = Vec::new();
+        self.for_each_triangle(heightmap, true, |_triangle, flat, _surface| result.push(flat));
+        result
+    }
+
+    /// Like `Heightmap::write_stl`, but wrapped.
+    /// Facets are colored by the flat triangles they came from.
+    pub fn write_stl<W: Write>(&self, heightmap: &Heightmap, writer: &mut StlWriter<W>, colors: &FacetColors)
+    -> Result<(), std::io::Error> {
+        let mut result = Ok(());
+        self.for_each_triangle(heightmap, colors.by_sample(), |triangle, flat, surface| {
+            if result.is_ok() {
+                result = match colors.color(heightmap, &flat, surface) {
+                    Some(color) => writer.write_colored_triangle(&triangle, color),
+                    None => writer.write_triangle(&triangle)
+                };
+            }
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::units::Unit;
+
+    fn heightmap() -> Heightmap {
+        let size = TVec2::new(8, 6);
+        Heightmap {
+            size,
+            scale: Vec2::new(0.5, 0.5),
+            // Columns of the same height, so that rows combine and walls between them are skipped,
+            // with the same height on either side of the seam
+            samples: (0..size.x * size.y).map(|k| 1. + (k % size.x / 3 % 2) as f32).collect(),
+            colors: None,
+            coverage: None,
+            invert_y: false,
+            unit: Unit::Millimeter
+        }
+    }
+
+    /// Checks that no triangle of the wrapped solid is degenerate, and that it encloses some volume
+    fn assert_has_area(wrap: &Wrap) {
+        let mut volume = 0.;
+        for triangle in wrap.get_triangles(&heightmap()) {
+            assert_ne!((triangle[1] - triangle[0]).cross(&(triangle[2] - triangle[0])), Vec3::zeros());
+            volume += triangle[0].dot(&triangle[1].cross(&triangle[2])) / 6.;
+        }
+        assert!(volume > 0.);
+    }
+
+    #[test]
+    fn arc_has_no_degenerate_triangles() {
+        assert_has_area(&Wrap::default());
+    }
+
+    #[test]
+    fn cylinder_seam_has_no_degenerate_triangles() {
+        assert_has_area(&Wrap { shape: WrapShape::Cylinder, ..Wrap::default() });
+    }
+
+    #[test]
+    fn sphere_poles_have_no_degenerate_triangles() {
+        assert_has_area(&Wrap { shape: WrapShape::Sphere, ..Wrap::default() });
+    }
+
+    #[test]
+    fn closed_curve_seam_has_no_degenerate_triangles() {
+        let points = vec![[0., 0.], [10., 0.], [10., 10.], [0., 0.]];
+        assert_has_area(&Wrap { shape: WrapShape::Curve, points, ..Wrap::default() });
+    }
+}
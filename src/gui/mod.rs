@@ -0,0 +1,283 @@
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::Mutex;
+use eframe::egui;
+use egui::Vec2;
+use nalgebra_glm::Vec3;
+use eframe::egui_glow;
+use egui_glow::glow;
+use crate::geometry::heightmap::*;
+use crate::geometry::ply::*;
+use crate::export::*;
+use crate::preset::Preset;
+use crate::settings::Settings;
+use crate::watch::*;
+pub mod mesh_view;
+
+struct AppState {
+    renderable_mesh: Option<Arc<Mutex<mesh_view::RenderableMesh>>>,
+    /// Translation that centers the previewed mesh
+    mesh_translation: Vec3,
+    gl: Arc<glow::Context>,
+    heightmap_path: Option<String>,
+    heightmap: Option<Heightmap>,
+    settings: Settings,
+    export_options: ExportOptions,
+    /// File the mesh was last exported to
+    export_path: Option<String>,
+    /// Reload (and re-export) the heightmap when its file changes
+    auto_reload: bool,
+    watcher: Option<FileWatcher>,
+    error: Option<String>
+}
+
+impl AppState {
+    fn new(gl: Arc<glow::Context>, preset: Preset) -> Self {
+        Self {
+            renderable_mesh: None,
+            mesh_translation: Vec3::zeros(),
+            gl,
+            heightmap_path: None,
+            heightmap: None,
+            settings: preset.settings,
+            export_options: preset.export,
+            export_path: None,
+            auto_reload: false,
+            watcher: None,
+            error: None
+        }
+    }
+
+    /// Replaces the current heightmap with the one at `path`
+    fn load(&mut self, path: String) {
+        if self.heightmap_path.as_ref() != Some(&path) {
+            self.export_path = None;
+        }
+        self.watcher = Some(FileWatcher::new(&path));
+        self.heightmap = None;
+        self.renderable_mesh = None;
+        self.error = None;
+        match self.settings.load(&path) {
+            Ok(heightmap) => {
+                self.heightmap = Some(heightmap);
+            },
+            Err(e) => {
+                self.error = Some(format!("Error Importing:\n\t{}\n", e));
+            }
+        }
+        self.heightmap_path = Some(path);
+    }
+
+    /// Reloads the heightmap if auto reload is on and its file has changed,
+    /// then exports it again if it has been exported
+    fn check_for_changes(&mut self) {
+        if !self.auto_reload {
+            return;
+        }
+        let changed = self.watcher.as_mut().is_some_and(|watcher| watcher.poll());
+        if let (true, Some(path)) = (changed, self.heightmap_path.clone()) {
+            self.load(path);
+            if let (Some(heightmap), Some(output_file)) = (&self.heightmap, &self.export_path) {
+                self.error = export_error(heightmap, self.heightmap_path.as_deref(), &self.settings,
+                    output_file, &self.export_options);
+            }
+        }
+    }
+}
+
+/// Exports the heightmap in the format of the file's extension, returning the error message if any
+fn export_error(
+    heightmap: &Heightmap,
+    source: Option<&str>,
+    settings: &Settings,
+    output_file: &str,
+    options: &ExportOptions)
+-> Option<String> {
+    let format = OutputFormat::from_path(output_file);
+    export(heightmap, source, Some(settings), output_file, format, options).err()
+        .map(|e| format!("Error Exporting:\n\t{}\n", e))
+}
+
+/// Translation that moves the middle of the triangles' x and y extent to the origin
+fn centering_translation(triangles: &[mesh_view::Triangle]) -> Vec3 {
+    let mut vertices = triangles.iter().flatten();
+    match vertices.next() {
+        Some(first) => {
+            let (min, max) = vertices.fold((*first, *first), |(min, max), v| (min.inf(v), max.sup(v)));
+            let middle = (min + max) * 0.5;
+            Vec3::new(-middle.x, -middle.y, 0.0)
+        },
+        None => Vec3::zeros()
+    }
+}
+
+impl eframe::App for AppState {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.check_for_changes();
+        if self.auto_reload {
+            ctx.request_repaint_after(POLL_INTERVAL);
+        }
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Heightmap To STL");
+
+            // File Selection
+            ui.horizontal(|ui| {
+                if ui.button("Select File").clicked() {
+                    if let Some(rfd_result) = rfd::FileDialog::new().pick_file() {
+                        self.load(rfd_result.display().to_string());
+                    }
+                }
+                if ui.button("Load Preset").clicked() {
+                    if let Some(rfd_result) = rfd::FileDialog::new()
+                        .add_filter("Preset", &["toml"])
+                        .pick_file()
+                    {
+                        match Preset::read(&rfd_result.display().to_string()) {
+                            Ok(preset) => {
+                                self.settings = preset.settings;
+                                self.export_options = preset.export;
+                                if let Some(path) = self.heightmap_path.clone() {
+                                    self.load(path);
+                                }
+                            },
+                            Err(e) => {
+                                self.error = Some(format!("Error Loading Preset:\n\t{}\n", e));
+                            }
+                        }
+                    }
+                }
+                ui.checkbox(&mut self.auto_reload, "Auto reload");
+                if ui.button("Save Preset").clicked() {
+                    if let Some(rfd_result) = rfd::FileDialog::new()
+                        .add_filter("Preset", &["toml"])
+                        .save_file()
+                    {
+                        let preset = Preset {
+                            settings: self.settings.clone(),
+                            export: self.export_options.clone()
+                        };
+                        if let Err(e) = preset.write(&rfd_result.display().to_string()) {
+                            self.error = Some(format!("Error Saving Preset:\n\t{}\n", e));
+                        }
+                    }
+                }
+            });
+
+            ui.horizontal(|ui| {
+                if let Some(heightmap_path) = &self.heightmap_path {
+                    ui.label("File: ");
+                    ui.monospace(heightmap_path);
+                }
+            });
+
+            // Error Message
+            // If there's an error, don't show any option but file selection
+            if let Some(error) = &self.error {
+                ui.label("Error:");
+                ui.monospace(error);
+                return;
+            }
+
+            if let Some(heightmap) = &self.heightmap {
+                ui.horizontal(|ui| {
+                    let options = &mut self.export_options;
+                    ui.label("Wall color:");
+                    ui.color_edit_button_srgb(&mut options.wall_color);
+                    ui.label("base color:");
+                    ui.color_edit_button_srgb(&mut options.base_color);
+                });
+                ui.horizontal(|ui| {
+                    let options = &mut self.export_options;
+                    let mut ascii = options.ply_format == PlyFormat::Ascii;
+                    if ui.checkbox(&mut ascii, "ASCII PLY").changed() {
+                        options.ply_format = if ascii {
+                            PlyFormat::Ascii
+                        } else {
+                            PlyFormat::BinaryLittleEndian
+                        };
+                    }
+                    ui.checkbox(&mut options.glb_vertex_colors, "GLB vertex colors");
+                    ui.checkbox(&mut options.glb_texture, "GLB texture");
+                });
+                ui.horizontal(|ui| {
+                    let options = &mut self.export_options;
+                    ui.label("STL colors:");
+                    ui.radio_value(&mut options.stl_colors, StlColors::None, "None");
+                    ui.radio_value(&mut options.stl_colors, StlColors::Image, "Image");
+                    ui.radio_value(&mut options.stl_colors, StlColors::HeightBands, "Height bands");
+                });
+                if ui.button("Export").clicked() {
+
+                    if let Some(rfd_result) = rfd::FileDialog::new().save_file() {
+                        let output_file = rfd_result.display().to_string();
+                        self.error = export_error(heightmap, self.heightmap_path.as_deref(), &self.settings,
+                            &output_file, &self.export_options);
+                        self.export_path = Some(output_file);
+                    }
+                }
+                if self.renderable_mesh.is_none() {
+                    let mesh_gl = self.gl.to_owned();
+                    let heightmap_mesh = mesh_triangles(heightmap, &self.export_options)
+                        .map_err(|e| e.to_string());
+                    match heightmap_mesh.and_then(|triangles| {
+                        self.mesh_translation = centering_translation(&triangles);
+                        mesh_view::RenderableMesh::new(mesh_gl, &triangles)
+                    }) {
+                        Ok (mut mesh) => {
+                            mesh.translation = self.mesh_translation;
+                            self.renderable_mesh = Some(Arc::new(Mutex::new(mesh)));
+                        },
+                        Err (e) => {
+                            self.error = Some(format!("Error creating mesh:\n\t{}\n", e));
+                        }
+                    };
+                }
+                if let Some(mesh) = &self.renderable_mesh {
+                    let mut style = (*ctx.style()).clone();
+                    style.spacing.slider_width = 350.;
+                    ctx.set_style(style);
+                    ui.vertical_centered(|ui| {
+                        ui.add(mesh_view::MeshView::new(Vec2::new(400., 400.), mesh.to_owned()));
+                        ui.horizontal(|ui| {
+                            match mesh.lock() {
+                                Ok(mut mesh) => {
+                                    if ui.button("reset").clicked() {
+                                        mesh.scale = 1.0;
+                                        mesh.reset_rotation();
+                                        mesh.translation = self.mesh_translation;
+                                    }
+                                    ui.add(egui::Slider::new(&mut mesh.scale, 0.0..=2.0));
+                                },
+                                Err(e) => {
+                                    self.error = Some(format!("Mesh panicked:\n\t{}\n", e));
+                                }
+                            }
+                        })
+                    });
+                }
+            }
+        });
+    }
+}
+
+/// Opens the graphical interface, optionally with a heightmap loaded
+pub fn run_gui(path: Option<String>, preset: Preset) -> ExitCode {
+    let options = eframe::NativeOptions {
+        initial_window_size: Some(egui::vec2(500., 600.)),
+        ..Default::default()
+    };
+    eframe::run_native(
+        "Heightmap To STL",
+        options,
+        Box::new(|cc| {
+            let mut app = AppState::new(
+                cc.gl.to_owned().expect("Could not get gl context"),
+                preset);
+            if let Some(path) = path {
+                app.load(path);
+            }
+            Box::new(app)
+        })
+    );
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,26 @@
+//! Converts heightmaps (images or text files) into printable meshes.
+//!
+//! `geometry` holds the heightmap, meshing and file format code;
+//! the other modules are the settings, export and command line layers
+//! built on it.
+//!
+//! Features (all on by default):
+//! - `image-formats`: reading heightmaps from images, and GLB textures
+//! - `cli`: the `cli` and `batch` modules, and the `heightmap_to_stl` binary
+//! - `gui`: the egui app, which needs display libraries
+//! - `parallel`: meshing large heightmaps on all cores
+//!
+//! With none of them, the crate builds without any windowing dependencies
+//! and reads text heightmaps only.
+
+pub mod geometry;
+pub mod settings;
+pub mod preset;
+pub mod export;
+pub mod watch;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "cli")]
+pub mod batch;
+#[cfg(feature = "gui")]
+pub mod gui;
@@ -0,0 +1,50 @@
+use std::fmt;
+use std::fs;
+use serde::{Deserialize, Serialize};
+use crate::export::ExportOptions;
+use crate::settings::Settings;
+
+/// Import, meshing and export settings, saved as a TOML file
+/// so that an export can be reproduced.
+///
+/// Anything missing from the file keeps its default value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Preset {
+    pub settings: Settings,
+    pub export: ExportOptions
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    IO(std::io::Error),
+    Parse(String)
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::IO(e) => write!(f, "Read/Write Error: {}", e),
+            Self::Parse(message) => write!(f, "Invalid preset: {}", message)
+        }
+    }
+}
+
+impl From<std::io::Error> for PresetError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IO(e)
+    }
+}
+
+impl Preset {
+    pub fn read(path: &str) -> Result<Self, PresetError> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| PresetError::Parse(e.to_string()))
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), PresetError> {
+        let text = toml::to_string(self).map_err(|e| PresetError::Parse(e.to_string()))?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+}
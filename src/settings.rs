@@ -0,0 +1,122 @@
+use std::fs::File;
+extern crate nalgebra_glm as glm;
+use glm::Vec2;
+use serde::{Deserialize, Serialize};
+use crate::geometry::ReadError;
+use crate::geometry::heightmap::*;
+use crate::geometry::units::Unit;
+use crate::geometry::mask::Mask;
+
+/// How to read a heightmap file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputFormat {
+    /// Detect the format from the file contents
+    Auto,
+    Image,
+    /// The text format: size, scale, then one sample per line
+    Text
+}
+
+/// Everything that controls how a heightmap file becomes a mesh
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    pub input_format: InputFormat,
+    /// Size of one sample along x and y.
+    /// If None, the scale from the file is used (1, 1 for images).
+    pub scale: Option<Vec2>,
+    /// Multiplies every height
+    pub z_scale: f32,
+    /// Unit of the scale, heights and sizes, recorded in formats that have units
+    pub unit: Unit,
+    /// Footprint to scale the heightmap to fit in (keeping its proportions),
+    /// applied after `scale`. Zero leaves a direction unconstrained.
+    pub fit: Option<Vec2>,
+    /// Height to scale the highest sample to, applied after `z_scale`
+    pub relief: Option<f32>,
+    /// Snaps the heights to this many evenly spaced levels, up to the highest sample,
+    /// for a terraced model. Applied after `relief`.
+    pub terraces: Option<u32>,
+    /// Snaps the heights to multiples of this, such as a printer's layer height.
+    /// Used instead of `terraces` if both are set.
+    pub layer_height: Option<f32>,
+    /// Thickness of a solid base added under the heightmap
+    pub base: f32,
+    // Last, as they are tables in preset files
+    pub image: ImageOptions,
+    /// Samples to leave out, so the object isn't rectangular
+    pub mask: Option<Mask>
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            input_format: InputFormat::Auto,
+            scale: None,
+            z_scale: 1.,
+            unit: Unit::Millimeter,
+            fit: None,
+            relief: None,
+            terraces: None,
+            layer_height: None,
+            base: 0.,
+            image: ImageOptions::default(),
+            mask: None
+        }
+    }
+}
+
+impl Settings {
+    /// Reads a heightmap file, and applies the mask, scale and base settings
+    pub fn load(&self, path: &str) -> Result<Heightmap, ReadError> {
+        let heightmap = match self.input_format {
+            InputFormat::Auto => load_heightmap(path, &self.image)?,
+            InputFormat::Image => read_heightmap_image(path, &self.image)?,
+            InputFormat::Text => read_heightmap(File::open(path)?)?
+        };
+        self.apply(heightmap)
+    }
+
+    /// Reads a heightmap from the contents of a file, and applies the mask, scale and base settings
+    pub fn load_bytes(&self, bytes: &[u8]) -> Result<Heightmap, ReadError> {
+        let heightmap = match self.input_format {
+            InputFormat::Auto => load_heightmap_bytes(bytes, &self.image)?,
+            InputFormat::Image => read_heightmap_image_bytes(bytes, &self.image)?,
+            InputFormat::Text => read_heightmap(bytes)?
+        };
+        self.apply(heightmap)
+    }
+
+    fn apply(&self, mut heightmap: Heightmap) -> Result<Heightmap, ReadError> {
+        // First, so that samples left out don't count towards the relief
+        if let Some(mask) = &self.mask {
+            mask.apply(&mut heightmap)?;
+        }
+        heightmap.unit = self.unit;
+        if let Some(scale) = self.scale {
+            heightmap.scale = scale;
+        }
+        if let Some(fit) = self.fit {
+            heightmap.fit_footprint(fit);
+        }
+        if self.z_scale != 1. {
+            heightmap.scale_heights(self.z_scale);
+        }
+        if let Some(relief) = self.relief {
+            heightmap.fit_relief(relief);
+        }
+        match (self.layer_height, self.terraces) {
+            (Some(step), _) => heightmap.terrace(step),
+            (None, Some(levels)) => {
+                let max = heightmap.samples.iter().copied().fold(0., f32::max);
+                heightmap.terrace(max / levels.max(1) as f32);
+            },
+            (None, None) => ()
+        }
+        if self.base != 0. {
+            heightmap.add_base(self.base);
+        }
+        Ok(heightmap)
+    }
+}
@@ -0,0 +1,57 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often to check watched files
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a file must stay unchanged before it counts as saved,
+/// so that editors writing a file in several steps only trigger one reload
+pub const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Modification time and size of a file
+type Stamp = (SystemTime, u64);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Notices when a file changes on disk, by polling its modification time and size
+pub struct FileWatcher {
+    path: PathBuf,
+    last: Option<Stamp>,
+    /// A change that hasn't settled yet, and when it was first seen
+    pending: Option<(Instant, Stamp)>
+}
+
+impl FileWatcher {
+    pub fn new(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        let last = stamp(&path);
+        Self { path, last, pending: None }
+    }
+
+    /// True once the file has changed, and then stayed the same for `DEBOUNCE`.
+    ///
+    /// A missing file (as while some editors save) is not a change.
+    pub fn poll(&mut self) -> bool {
+        let Some(current) = stamp(&self.path) else {
+            return false;
+        };
+        if self.last == Some(current) {
+            self.pending = None;
+            return false;
+        }
+        match self.pending {
+            Some((seen, pending)) if pending == current => {
+                if seen.elapsed() >= DEBOUNCE {
+                    self.last = Some(current);
+                    self.pending = None;
+                    return true;
+                }
+            },
+            _ => self.pending = Some((Instant::now(), current))
+        }
+        false
+    }
+}